@@ -0,0 +1,63 @@
+// Conversions between `DateWithPrecision` and chrono dates, behind the "chrono" feature
+
+use super::dates::{DateError, DatePrecision, DateWithPrecision};
+use chrono::{Datelike, NaiveDate};
+
+impl TryFrom<NaiveDate> for DateWithPrecision {
+    type Error = DateError;
+
+    // fails for years outside of 0..=65535
+    fn try_from(date: NaiveDate) -> Result<DateWithPrecision, DateError> {
+        let year =
+            u16::try_from(date.year()).map_err(|_| DateError::YearOutOfRange(date.year()))?;
+        DateWithPrecision::new(
+            year,
+            date.month() as u16,
+            date.day() as u16,
+            DatePrecision::Day,
+        )
+    }
+}
+
+impl DateWithPrecision {
+    // only dates with day precision are a single day
+    pub fn to_naive_date(self) -> Option<NaiveDate> {
+        if self.precision != DatePrecision::Day {
+            return None;
+        }
+        NaiveDate::from_ymd_opt(self.year.into(), self.month.into(), self.day.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn day_dates_round_trip() {
+        for (y, m, d) in [(2000, 2, 29), (1, 1, 1), (65535, 12, 31)] {
+            let naive = NaiveDate::from_ymd_opt(y, m, d).unwrap();
+            let date = DateWithPrecision::try_from(naive).unwrap();
+            assert_eq!(
+                date,
+                DateWithPrecision::new(y as u16, m as u16, d as u16, DatePrecision::Day).unwrap()
+            );
+            assert_eq!(date.to_naive_date(), Some(naive));
+        }
+    }
+
+    #[test]
+    fn out_of_range_and_coarse_dates() {
+        let naive = NaiveDate::from_ymd_opt(-1, 1, 1).unwrap();
+        assert_eq!(
+            DateWithPrecision::try_from(naive),
+            Err(DateError::YearOutOfRange(-1))
+        );
+        let naive = NaiveDate::from_ymd_opt(65536, 1, 1).unwrap();
+        assert!(DateWithPrecision::try_from(naive).is_err());
+        for precision in [DatePrecision::Year, DatePrecision::Month] {
+            let date = DateWithPrecision::new(2000, 1, 1, precision).unwrap();
+            assert_eq!(date.to_naive_date(), None);
+        }
+    }
+}
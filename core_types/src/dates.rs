@@ -0,0 +1,123 @@
+use core::cmp::Ordering;
+use core::fmt;
+use serde::{Deserialize, Serialize};
+
+#[derive(Copy, Clone, Debug, PartialOrd, Ord, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum DatePrecision {
+    Year,
+    Month,
+    Day,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct DateWithPrecision {
+    pub year: u16,
+    pub month: u16,
+    pub day: u16,
+    pub precision: DatePrecision,
+}
+
+// why a date was rejected, the server turns it into `InternalErr::Other` with the same message
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum DateError {
+    Month { year: u16, month: u16 },
+    Day { year: u16, month: u16, day: u16 },
+    YearOutOfRange(i32),
+}
+
+impl fmt::Display for DateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DateError::Month { year, month } => write!(f, "invalid month {month} in {year}"),
+            DateError::Day { year, month, day } => {
+                write!(f, "invalid day {day} in {year}-{month:02}")
+            }
+            DateError::YearOutOfRange(year) => write!(f, "year {year} out of range"),
+        }
+    }
+}
+
+fn days_in_month(year: u16, month: u16) -> u16 {
+    match month {
+        2 if year.is_multiple_of(4) && (!year.is_multiple_of(100) || year.is_multiple_of(400)) => {
+            29
+        }
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+impl DateWithPrecision {
+    // checked constructor for user input, components finer than the precision are zeroed
+    pub fn new(
+        year: u16,
+        month: u16,
+        day: u16,
+        precision: DatePrecision,
+    ) -> Result<DateWithPrecision, DateError> {
+        let month = if precision == DatePrecision::Year {
+            0
+        } else {
+            month
+        };
+        let day = if precision == DatePrecision::Day {
+            day
+        } else {
+            0
+        };
+        if precision != DatePrecision::Year && !(1..=12).contains(&month) {
+            return Err(DateError::Month { year, month });
+        }
+        if precision == DatePrecision::Day && !(1..=days_in_month(year, month)).contains(&day) {
+            return Err(DateError::Day { year, month, day });
+        }
+        Ok(DateWithPrecision {
+            year,
+            month,
+            day,
+            precision,
+        })
+    }
+
+    // Chronological order: year, then month, then day, where components finer than the
+    // precision are not known. A less precise date sorts before every more precise date within
+    // it, e.g. 2020 < 2020-01 < 2020-01-01 < 2020-03 < 2020-03-01 < 2021.
+    pub fn cmp_chronological(&self, other: &DateWithPrecision) -> Ordering {
+        self.sort_key().cmp(&other.sort_key())
+    }
+
+    // unknown components are 0, which is before every valid month and day; the raw fields come
+    // last so the order agrees with `Eq` for dates with stray components
+    fn sort_key(&self) -> (u16, u16, u16, DatePrecision, u16, u16) {
+        let month = if self.precision >= DatePrecision::Month {
+            self.month
+        } else {
+            0
+        };
+        let day = if self.precision >= DatePrecision::Day {
+            self.day
+        } else {
+            0
+        };
+        (self.year, month, day, self.precision, self.month, self.day)
+    }
+}
+
+impl Ord for DateWithPrecision {
+    fn cmp(&self, other: &DateWithPrecision) -> Ordering {
+        self.cmp_chronological(other)
+    }
+}
+
+impl PartialOrd for DateWithPrecision {
+    fn partial_cmp(&self, other: &DateWithPrecision) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialOrd, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Birthday {
+    pub month: u16,
+    pub day: u16,
+}
@@ -0,0 +1,87 @@
+use core::cmp::Ordering;
+use serde::{Deserialize, Serialize};
+
+#[derive(Copy, Clone, Debug, PartialOrd, Ord, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct ArtistId(pub usize);
+
+#[derive(Copy, Clone, Debug, PartialOrd, Ord, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct ReleaseId(pub usize);
+
+#[derive(Copy, Clone, Debug, PartialOrd, Ord, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct TagId(pub usize);
+
+#[derive(Copy, Clone, Debug, PartialOrd, Ord, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct EventId(pub usize);
+
+#[derive(Copy, Clone, Debug, PartialOrd, Ord, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct UserId(pub usize);
+
+#[derive(Copy, Clone, Debug, PartialOrd, Ord, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct FileId(pub usize);
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct TrackNum {
+    // 0 if there is no disc, otherwise starts from 1
+    pub disc_num: u16,
+    // also starts from 1...
+    pub track_num: u16,
+}
+
+impl TrackNum {
+    // Play order: disc, then track. A release normally either has no discs or numbers all of
+    // them, if it has both, the tracks without a disc are played first, as disc 0.
+    pub fn sort_key(&self) -> (u16, u16) {
+        (self.disc_num, self.track_num)
+    }
+}
+
+// play order, independent of the field order
+impl Ord for TrackNum {
+    fn cmp(&self, other: &TrackNum) -> Ordering {
+        self.sort_key().cmp(&other.sort_key())
+    }
+}
+
+impl PartialOrd for TrackNum {
+    fn partial_cmp(&self, other: &TrackNum) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+// serialized flat, as `{"release_id": _, "disc_num": _, "track_num": _}` in this order
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct TrackRef {
+    pub release_id: ReleaseId,
+    #[serde(flatten)]
+    pub track_num: TrackNum,
+}
+
+// release, then play order within the release, independent of the field order
+impl Ord for TrackRef {
+    fn cmp(&self, other: &TrackRef) -> Ordering {
+        (self.release_id, self.track_num.sort_key())
+            .cmp(&(other.release_id, other.track_num.sort_key()))
+    }
+}
+
+impl PartialOrd for TrackRef {
+    fn partial_cmp(&self, other: &TrackRef) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialOrd, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum ArtistKind {
+    Solo,
+    Group,
+}
+
+#[derive(Copy, Clone, Debug, PartialOrd, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum ReleaseKind {
+    Album,
+    Ep,
+    Single,
+    Compilation,
+    Demo,
+    Other,
+}
@@ -0,0 +1,16 @@
+// Plain value types of the catalog: ids, kinds and dates
+//
+// These only need `core` and `alloc`, which serde needs for flattened fields, so the domain model
+// can be shared with clients that have no `std`, e.g. WASM. Types that hold interned strings
+// (`Ustr`) or maps, and everything about `States`, stay in the server crate, which re-exports
+// these from `defs`.
+
+#![no_std]
+
+#[cfg(feature = "chrono")]
+mod chrono_dates;
+mod dates;
+mod ids;
+
+pub use dates::*;
+pub use ids::*;
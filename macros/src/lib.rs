@@ -1,66 +1,320 @@
-use heck::{ToUpperCamelCase, ToSnakeCase};
+use heck::{ToSnakeCase, ToUpperCamelCase};
 use proc_macro::TokenStream;
 use quote::{format_ident, quote};
-use syn::{parse_macro_input, Data, DeriveInput, Fields, Ident, Meta};
+use std::collections::HashMap;
+use syn::{
+    Data, DeriveInput, Field, Fields, GenericArgument, Ident, LitStr, PathArguments, Type,
+    parse_macro_input,
+};
 
-#[proc_macro_derive(DiffFields, attributes(skip_diff))]
+enum DiffKind<'a> {
+    // the whole field is replaced, the default
+    Whole,
+    // element-level operations on a `Vec<T>`, carries `T`
+    Vec(&'a Type),
+    // key-level operations on a map, see `MapField`
+    Map,
+}
+
+fn has_attr(field: &Field, name: &str) -> bool {
+    field.attrs.iter().any(|attr| attr.path().is_ident(name))
+}
+
+fn vec_elem(ty: &Type) -> Option<&Type> {
+    let Type::Path(path) = ty else {
+        return None;
+    };
+    let segment = path.path.segments.last()?;
+    if segment.ident != "Vec" {
+        return None;
+    }
+    let PathArguments::AngleBracketed(ref args) = segment.arguments else {
+        return None;
+    };
+    match args.args.first() {
+        Some(GenericArgument::Type(ty)) if args.args.len() == 1 => Some(ty),
+        _ => None,
+    }
+}
+
+// the variant of a field: `#[diff_rename("Name")]`, or the field name in UpperCamelCase
+fn variant_name(field: &Field) -> syn::Result<Ident> {
+    let ident = field.ident.as_ref().unwrap();
+    match field
+        .attrs
+        .iter()
+        .find(|attr| attr.path().is_ident("diff_rename"))
+    {
+        Some(attr) => {
+            let name: LitStr = attr.parse_args()?;
+            name.parse()
+        }
+        None => Ok(format_ident!("{}", ident.to_string().to_upper_camel_case())),
+    }
+}
+
+// `#[diff(only(a, b))]` on the struct: only the listed fields get variants, the field
+// attributes still apply to them
+fn diff_allowlist(input: &DeriveInput) -> syn::Result<Option<Vec<Ident>>> {
+    let mut allowlist = None;
+    for attr in input
+        .attrs
+        .iter()
+        .filter(|attr| attr.path().is_ident("diff"))
+    {
+        attr.parse_nested_meta(|meta| {
+            if !meta.path.is_ident("only") {
+                return Err(meta.error("expected `only(...)`"));
+            }
+            let fields = allowlist.get_or_insert_with(Vec::new);
+            meta.parse_nested_meta(|field| {
+                fields.push(field.path.require_ident()?.clone());
+                Ok(())
+            })
+        })?;
+    }
+    Ok(allowlist)
+}
+
+// Every named field gets a variant in the diff enum, whatever its type or other attributes,
+// unless it is `#[skip_diff]` or left out by `#[diff(only(...))]`. The diff enum derives `Hash`,
+// so the type of every field with a variant has to implement it. Skipping a field removes its
+// variant from the public diff enum.
+#[proc_macro_derive(
+    DiffFields,
+    attributes(diff, skip_diff, vec_diff, map_diff, diff_rename)
+)]
 pub fn derive_diffs(input: TokenStream) -> TokenStream {
     let input = parse_macro_input!(input as DeriveInput);
-    if let Data::Struct(ref data) = input.data {
-        if let Fields::Named(ref fields) = data.fields {
-            let owner = &input.ident;
-            let name = format_ident!("{}Diff", input.ident);
-            let map_ident =
-                |ident: &Ident| format_ident!("{}", ident.to_string().to_upper_camel_case());
-            let fields: Vec<_> = fields
+    TokenStream::from(diffs(&input).unwrap_or_else(|e| e.to_compile_error()))
+}
+
+// the derive on parsed input, so that its errors can be checked in tests
+fn diffs(input: &DeriveInput) -> syn::Result<proc_macro2::TokenStream> {
+    if let Data::Struct(ref data) = input.data
+        && let Fields::Named(ref fields) = data.fields
+    {
+        let owner = &input.ident;
+        let name = format_ident!("{}Diff", input.ident);
+        let mut diff_fields = Vec::new();
+        let mut skipped_fields = Vec::new();
+        let mut variant_names = HashMap::new();
+        let allowlist = diff_allowlist(input)?;
+        for allowed in allowlist.iter().flatten() {
+            if !fields
                 .named
                 .iter()
-                .flat_map(|field| {
-                    if field
-                        .attrs
-                        .iter()
-                        .find(|attr| {
-                            if let Meta::Path(ref p) = attr.meta {
-                                p.is_ident("skip_diff")
-                            } else {
-                                true
-                            }
-                        })
-                        .is_some()
-                    {
-                        None
-                    } else {
-                        Some((field.ident.as_ref().unwrap(), &field.ty))
+                .any(|field| field.ident.as_ref() == Some(allowed))
+            {
+                return Err(syn::Error::new_spanned(
+                    allowed,
+                    format!("`{owner}` has no field `{allowed}`"),
+                ));
+            }
+        }
+        for field in fields.named.iter() {
+            if let Some(allowlist) = &allowlist
+                && !allowlist.contains(field.ident.as_ref().unwrap())
+            {
+                continue;
+            }
+            if has_attr(field, "skip_diff") {
+                skipped_fields.push(field.ident.as_ref().unwrap().to_string());
+                continue;
+            }
+            let kind = if has_attr(field, "vec_diff") {
+                match vec_elem(&field.ty) {
+                    Some(elem) => DiffKind::Vec(elem),
+                    None => {
+                        return Err(syn::Error::new_spanned(
+                            &field.ty,
+                            "`vec_diff` can only be used on `Vec<T>` fields",
+                        ));
                     }
-                })
-                .collect();
-            let variants = fields.iter().map(|(ident, ty)| {
-                let name = map_ident(ident);
-                quote!(#name(#ty))
+                }
+            } else if has_attr(field, "map_diff") {
+                DiffKind::Map
+            } else {
+                DiffKind::Whole
+            };
+            let variant = variant_name(field)?;
+            // reported here rather than as a duplicate variant inside the generated enum
+            let ident = field.ident.as_ref().unwrap();
+            if let Some(other) = variant_names.insert(variant.to_string(), ident) {
+                return Err(syn::Error::new_spanned(
+                    ident,
+                    format!(
+                        "fields `{other}` and `{ident}` both map to the diff variant \
+                         `{variant}`, rename one with `#[diff_rename(\"...\")]`"
+                    ),
+                ));
+            }
+            diff_fields.push((field.ident.as_ref().unwrap(), &field.ty, kind, variant));
+        }
+        let variants = diff_fields.iter().map(|(_, ty, kind, name)| match kind {
+            DiffKind::Whole => quote!(#name(#ty)),
+            DiffKind::Vec(elem) => quote!(#name(VecOp<#elem>)),
+            DiffKind::Map => {
+                quote!(#name(MapOp<<#ty as MapField>::Key, <#ty as MapField>::Value>))
+            }
+        });
+        let match_arm = diff_fields
+            .iter()
+            .map(|(ident, _, kind, variant)| match kind {
+                DiffKind::Whole => quote!(#name::#variant(v) => { obj.#ident = v; }),
+                DiffKind::Vec(_) => quote!(#name::#variant(op) => op.apply(&mut obj.#ident)?),
+                DiffKind::Map => quote!(#name::#variant(op) => op.apply(&mut obj.#ident)),
+            });
+        // fields are compared in declaration order, so the output is deterministic
+        let compare = diff_fields
+            .iter()
+            .map(|(ident, _, kind, variant)| match kind {
+                DiffKind::Whole => quote!(
+                    if old.#ident != new.#ident {
+                        diffs.push(#name::#variant(new.#ident.clone()));
+                    }
+                ),
+                DiffKind::Vec(_) => quote!(
+                    diffs.extend(
+                        VecOp::diff(&old.#ident, &new.#ident)
+                            .into_iter()
+                            .map(#name::#variant),
+                    );
+                ),
+                DiffKind::Map => quote!(
+                    diffs.extend(
+                        MapOp::diff(&old.#ident, &new.#ident)
+                            .into_iter()
+                            .map(#name::#variant),
+                    );
+                ),
+            });
+        // the inverse of a diff carries the current value of the same field
+        let invert_arm = diff_fields
+            .iter()
+            .map(|(ident, _, kind, variant)| match kind {
+                DiffKind::Whole => {
+                    quote!(#name::#variant(_) => #name::#variant(obj.#ident.clone()))
+                }
+                DiffKind::Vec(_) => {
+                    quote!(#name::#variant(op) => #name::#variant(op.invert(&obj.#ident)?))
+                }
+                DiffKind::Map => {
+                    quote!(#name::#variant(op) => #name::#variant(op.invert(&obj.#ident)))
+                }
             });
-            let match_arm = fields.iter().map(|(ident, _)| {
-                let variant = map_ident(ident);
-                quote!(#name::#variant(v) => { obj.#ident = v; })
+        let noop_arm = diff_fields
+            .iter()
+            .map(|(ident, _, kind, variant)| match kind {
+                DiffKind::Whole => quote!(#name::#variant(v) => obj.#ident == *v),
+                DiffKind::Vec(_) | DiffKind::Map => {
+                    quote!(#name::#variant(op) => op.is_noop(&obj.#ident))
+                }
             });
-            let apply_fn = format_ident!("apply_{}", name.to_string().to_snake_case());
-            return TokenStream::from(quote!(
-                #[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
-                pub enum #name {
-                    #(#variants),*
+        let field_name_arm = diff_fields.iter().map(|(ident, _, _, variant)| {
+            let field_name = ident.to_string();
+            quote!(#name::#variant(_) => #field_name)
+        });
+        let field_names = diff_fields.iter().map(|(ident, ..)| ident.to_string());
+        let apply_fn = format_ident!("apply_{}", name.to_string().to_snake_case());
+        let invert_fn = format_ident!("invert_{}", name.to_string().to_snake_case());
+        let diffs_fn = format_ident!("{}_diffs", owner.to_string().to_snake_case());
+        let set_name = format_ident!("{}Set", name);
+        let apply_set_fn = format_ident!("apply_{}", set_name.to_string().to_snake_case());
+        return Ok(quote!(
+            #[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+            pub enum #name {
+                #(#variants),*
+            }
+            impl #name {
+                // names of the fields that have a variant, in declaration order
+                pub const FIELD_NAMES: &[&str] = &[#(#field_names),*];
+                // names of the `#[skip_diff]` fields, in declaration order
+                pub const SKIP_DIFF_FIELD_NAMES: &[&str] = &[#(#skipped_fields),*];
+                // name of the field this diff touches, as declared in the struct
+                pub fn field_name(&self) -> &'static str {
+                    match self {
+                        #(#field_name_arm),*
+                    }
                 }
-                pub fn #apply_fn(obj: &mut #owner, diff: #name) {
-                    match diff {
-                        #(#match_arm),*
+                // whether applying the diff to `obj` changes nothing
+                pub fn is_noop(&self, obj: &#owner) -> bool {
+                    match self {
+                        #(#noop_arm),*
                     }
                 }
-            ));
-        }
+            }
+            pub fn #apply_fn(obj: &mut #owner, diff: #name) -> Result<(), InternalErr> {
+                match diff {
+                    #(#match_arm),*
+                }
+                Ok(())
+            }
+            impl #owner {
+                // same as the free function, for method syntax
+                pub fn apply_diff(&mut self, diff: #name) -> Result<(), InternalErr> {
+                    #apply_fn(self, diff)
+                }
+            }
+            // all changes of one edit, serialized as a plain list of diffs
+            #[derive(Clone, Debug, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+            pub struct #set_name(pub Vec<#name>);
+            // applies the diffs in order, stopping at the first one that fails
+            pub fn #apply_set_fn(obj: &mut #owner, set: #set_name) -> Result<(), InternalErr> {
+                for diff in set.0 {
+                    #apply_fn(obj, diff)?;
+                }
+                Ok(())
+            }
+            pub fn #invert_fn(obj: &#owner, diff: &#name) -> Result<#name, InternalErr> {
+                Ok(match diff {
+                    #(#invert_arm),*
+                })
+            }
+            pub fn #diffs_fn(old: &#owner, new: &#owner) -> Vec<#name> {
+                let mut diffs = Vec::new();
+                #(#compare)*
+                diffs
+            }
+        ));
+    }
+    Err(syn::Error::new(
+        input.ident.span(),
+        "Only structs with named fields can derive `FromRow`",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use syn::parse_quote;
+
+    #[test]
+    fn variant_collisions_name_both_fields() {
+        let input: DeriveInput = parse_quote! {
+            struct Song {
+                title: String,
+                #[diff_rename("Title")]
+                name: String,
+            }
+        };
+        let err = diffs(&input).unwrap_err().to_string();
+        assert_eq!(
+            err,
+            "fields `title` and `name` both map to the diff variant `Title`, rename one with \
+             `#[diff_rename(\"...\")]`"
+        );
+    }
+
+    #[test]
+    fn allowlist_entries_have_to_be_fields() {
+        let input: DeriveInput = parse_quote! {
+            #[diff(only(title, name))]
+            struct Song {
+                title: String,
+            }
+        };
+        let err = diffs(&input).unwrap_err().to_string();
+        assert_eq!(err, "`Song` has no field `name`");
     }
-    TokenStream::from(
-        syn::Error::new(
-            input.ident.span(),
-            "Only structs with named fields can derive `FromRow`",
-        )
-        .to_compile_error(),
-    )
 }
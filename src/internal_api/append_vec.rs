@@ -0,0 +1,163 @@
+// Append-only vector for entity storage
+//
+// Elements live in chunks that are never moved or freed, chunk `k` holds `FIRST_CHUNK << k`
+// elements, so a push never relocates existing elements and only needs `&self`. Pushes are
+// serialized by an internal lock, readers only see elements whose push has completed.
+
+use std::convert::Infallible;
+use std::ops::{Index, IndexMut};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Mutex, OnceLock};
+
+const FIRST_CHUNK: usize = 32;
+// enough chunks for any index that fits in a usize
+const CHUNKS: usize = usize::BITS as usize - FIRST_CHUNK.trailing_zeros() as usize;
+
+type Chunk<T> = Box<[OnceLock<T>]>;
+
+pub struct AppendVec<T> {
+    chunks: [OnceLock<Chunk<T>>; CHUNKS],
+    // number of elements that are pushed and visible to readers
+    len: AtomicUsize,
+    push_lock: Mutex<()>,
+}
+
+// chunk and offset in the chunk of element `i`
+fn locate(i: usize) -> (usize, usize) {
+    let n = i / FIRST_CHUNK + 1;
+    let chunk = (usize::BITS - 1 - n.leading_zeros()) as usize;
+    (chunk, i - ((1 << chunk) - 1) * FIRST_CHUNK)
+}
+
+impl<T> AppendVec<T> {
+    pub fn new() -> AppendVec<T> {
+        AppendVec {
+            chunks: std::array::from_fn(|_| OnceLock::new()),
+            len: AtomicUsize::new(0),
+            push_lock: Mutex::new(()),
+        }
+    }
+
+    pub fn with_capacity(capacity: usize) -> AppendVec<T> {
+        let v = AppendVec::new();
+        v.reserve(capacity);
+        v
+    }
+
+    fn chunk(&self, chunk: usize) -> &Chunk<T> {
+        self.chunks[chunk]
+            .get_or_init(|| (0..FIRST_CHUNK << chunk).map(|_| OnceLock::new()).collect())
+    }
+
+    // allocates the chunks for `additional` more elements
+    pub fn reserve(&self, additional: usize) {
+        let end = self.len() + additional;
+        if end > 0 {
+            for chunk in 0..=locate(end - 1).0 {
+                self.chunk(chunk);
+            }
+        }
+    }
+
+    // number of elements that can be stored without allocating
+    pub fn capacity(&self) -> usize {
+        let allocated = self.chunks.iter().take_while(|c| c.get().is_some()).count();
+        ((1 << allocated) - 1) * FIRST_CHUNK
+    }
+
+    pub fn len(&self) -> usize {
+        self.len.load(Ordering::Acquire)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn get(&self, i: usize) -> Option<&T> {
+        if i >= self.len() {
+            return None;
+        }
+        let (chunk, offset) = locate(i);
+        self.chunks[chunk].get()?[offset].get()
+    }
+
+    pub fn get_mut(&mut self, i: usize) -> Option<&mut T> {
+        if i >= self.len() {
+            return None;
+        }
+        let (chunk, offset) = locate(i);
+        self.chunks[chunk].get_mut()?[offset].get_mut()
+    }
+
+    pub fn push(&self, value: T) -> usize {
+        let Ok(i) = self.push_with(|_| Ok::<_, Infallible>(value));
+        i
+    }
+
+    // `make` gets the index of the new element, and runs under the push lock, so the order of
+    // the side effects in `make` is the order of the indices
+    // nothing is pushed if `make` fails
+    pub fn push_with<E>(&self, make: impl FnOnce(usize) -> Result<T, E>) -> Result<usize, E> {
+        // the guarded data is `()`, a poisoned lock has nothing to repair
+        let _guard = self.push_lock.lock().unwrap_or_else(|e| e.into_inner());
+        let i = self.len.load(Ordering::Relaxed);
+        let value = make(i)?;
+        let (chunk, offset) = locate(i);
+        if self.chunk(chunk)[offset].set(value).is_err() {
+            unreachable!("slot {i} is pushed twice");
+        }
+        self.len.store(i + 1, Ordering::Release);
+        Ok(i)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &T> {
+        (0..self.len()).filter_map(|i| self.get(i))
+    }
+
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = &mut T> {
+        let len = self.len();
+        self.chunks
+            .iter_mut()
+            .filter_map(|chunk| chunk.get_mut())
+            .flat_map(|chunk| chunk.iter_mut())
+            .take(len)
+            .filter_map(|slot| slot.get_mut())
+    }
+}
+
+impl<T> Default for AppendVec<T> {
+    fn default() -> AppendVec<T> {
+        AppendVec::new()
+    }
+}
+
+impl<T> Index<usize> for AppendVec<T> {
+    type Output = T;
+
+    fn index(&self, i: usize) -> &T {
+        match self.get(i) {
+            Some(value) => value,
+            None => panic!("index {i} out of bounds, len {}", self.len()),
+        }
+    }
+}
+
+impl<T> IndexMut<usize> for AppendVec<T> {
+    fn index_mut(&mut self, i: usize) -> &mut T {
+        let len = self.len();
+        match self.get_mut(i) {
+            Some(value) => value,
+            None => panic!("index {i} out of bounds, len {len}"),
+        }
+    }
+}
+
+impl<T> FromIterator<T> for AppendVec<T> {
+    fn from_iter<I: IntoIterator<Item = T>>(iter: I) -> AppendVec<T> {
+        let v = AppendVec::new();
+        for value in iter {
+            v.push(value);
+        }
+        v
+    }
+}
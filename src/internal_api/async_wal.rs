@@ -0,0 +1,236 @@
+// Logging to an asynchronous store, e.g. a remote log service
+//
+// `States` records while holding entity locks, so it cannot await a store directly.
+// `QueuedLogStore` is a `LogStore` that assigns the sequence number and timestamp in `record` and
+// only queues the record, `flush` then ships the queued records to the `AsyncLogStore` one at a
+// time in sequence order. Records are durable only once `flush` returns, so a request handler
+// that needs durability awaits `flush` before replying, without blocking other handlers.
+//
+// The reads of `LogStore`, e.g. for `compact`, flush and read the store by blocking the calling
+// thread on the futures, so they must not be called from an async task: await `read_all` there.
+
+use super::UserId;
+use super::wal::{Clock, LogRecord, LogStore, SystemClock};
+use serde::Serialize;
+use serde_json::to_string;
+use std::collections::VecDeque;
+use std::pin::pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Wake, Waker};
+use std::thread::{self, Thread};
+
+pub trait AsyncLogStore {
+    // records arrive in sequence order, one at a time
+    fn record(&self, record: LogRecord) -> impl Future<Output = Result<(), String>> + Send;
+
+    // all records in log order
+    fn read_all(&self) -> impl Future<Output = Result<Vec<LogRecord>, String>> + Send;
+}
+
+// keeps the records in memory, e.g. for tests, a remote store has the same shape with the
+// requests to the service in place of the lock
+#[derive(Debug, Default)]
+pub struct MemoryAsyncLogStore {
+    records: tokio::sync::Mutex<Vec<LogRecord>>,
+}
+
+impl AsyncLogStore for MemoryAsyncLogStore {
+    async fn record(&self, record: LogRecord) -> Result<(), String> {
+        self.records.lock().await.push(record);
+        Ok(())
+    }
+
+    async fn read_all(&self) -> Result<Vec<LogRecord>, String> {
+        Ok(self.records.lock().await.clone())
+    }
+}
+
+pub struct QueuedLogStore<A: AsyncLogStore, C: Clock = SystemClock> {
+    store: A,
+    // records not yet in `store`, and the sequence number of the next record
+    // never locked across an await
+    queue: Mutex<(VecDeque<LogRecord>, u64)>,
+    // held for a whole flush, so concurrent flushes cannot reorder records
+    flushing: tokio::sync::Mutex<()>,
+    clock: C,
+}
+
+impl<A: AsyncLogStore> QueuedLogStore<A> {
+    // `next_seq` continues the sequence numbers of the records already in `store`
+    pub fn new(store: A, next_seq: u64) -> QueuedLogStore<A> {
+        QueuedLogStore::with_clock(store, next_seq, SystemClock)
+    }
+}
+
+impl<A: AsyncLogStore, C: Clock> QueuedLogStore<A, C> {
+    pub fn with_clock(store: A, next_seq: u64, clock: C) -> QueuedLogStore<A, C> {
+        QueuedLogStore {
+            store,
+            queue: Mutex::new((VecDeque::new(), next_seq)),
+            flushing: tokio::sync::Mutex::new(()),
+            clock,
+        }
+    }
+
+    // ships every record queued before the call, a record is dequeued only once stored, so a
+    // failed flush can be retried
+    // records queued during the flush are left for the next one, so it returns even while
+    // writers keep queuing
+    pub async fn flush(&self) -> Result<(), String> {
+        let _flushing = self.flushing.lock().await;
+        let queued = self.queue.lock().map_err(|_| "Poison".to_owned())?.0.len();
+        for _ in 0..queued {
+            // only a flush dequeues, and flushes do not overlap
+            let record = {
+                let queue = self.queue.lock().map_err(|_| "Poison".to_owned())?;
+                queue.0[0].clone()
+            };
+            self.store.record(record).await?;
+            let mut queue = self.queue.lock().map_err(|_| "Poison".to_owned())?;
+            queue.0.pop_front();
+        }
+        Ok(())
+    }
+
+    pub async fn read_all(&self) -> Result<Vec<LogRecord>, String> {
+        self.flush().await?;
+        self.store.read_all().await
+    }
+}
+
+impl<A: AsyncLogStore, C: Clock> LogStore for QueuedLogStore<A, C> {
+    fn record<T: Serialize>(
+        &self,
+        user: UserId,
+        api_name: &str,
+        payload: &T,
+    ) -> Result<u64, String> {
+        let payload = to_string(payload).map_err(|e| e.to_string())?;
+        let mut queue = self.queue.lock().map_err(|_| "Poison".to_owned())?;
+        let (records, next_seq) = &mut *queue;
+        let seq = *next_seq;
+        records.push_back(LogRecord {
+            seq,
+            timestamp: self.clock.now(),
+            user,
+            api_name: api_name.into(),
+            payload,
+        });
+        *next_seq += 1;
+        Ok(seq)
+    }
+
+    // blocks the calling thread, see the top of the file
+    fn read_all(&self) -> Result<Vec<LogRecord>, String> {
+        block_on(QueuedLogStore::read_all(self))
+    }
+}
+
+// drives `future` to completion on the calling thread, parking it while the future is pending
+fn block_on<F: Future>(future: F) -> F::Output {
+    struct Unpark(Thread);
+
+    impl Wake for Unpark {
+        fn wake(self: Arc<Self>) {
+            self.0.unpark();
+        }
+    }
+
+    let waker = Waker::from(Arc::new(Unpark(thread::current())));
+    let mut cx = Context::from_waker(&waker);
+    let mut future = pin!(future);
+    loop {
+        match future.as_mut().poll(&mut cx) {
+            Poll::Ready(output) => return output,
+            Poll::Pending => thread::park(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::States;
+    use super::*;
+    use std::sync::OnceLock;
+
+    #[test]
+    fn log_store_reads_flush_first() {
+        let wal = QueuedLogStore::new(MemoryAsyncLogStore::default(), 0);
+        let states = States::new(&wal);
+        let user = UserId(1);
+        let id = states.artist_add(user, "artist".to_owned()).unwrap();
+        states.artist_remove(user, id).unwrap();
+        states.tag_add(UserId(2), "tag".to_owned()).unwrap();
+
+        let records = LogStore::read_all(&wal).unwrap();
+        assert_eq!(records.iter().map(|r| r.seq).collect::<Vec<_>>(), [0, 1, 2]);
+        assert_eq!(wal.read_for_user(UserId(2)).unwrap().len(), 1);
+        let compacted = states.compact(&records).unwrap();
+        let replayed = States::replay(&wal, &compacted).unwrap();
+        assert_eq!(replayed.diff_against(&states).unwrap(), Vec::new());
+    }
+
+    #[test]
+    fn concurrent_handlers_flush_in_order() {
+        let wal = QueuedLogStore::new(MemoryAsyncLogStore::default(), 0);
+        let states = States::new(&wal);
+        // each handler on its own runtime, recording and flushing concurrently with the others
+        thread::scope(|s| {
+            for i in 0..8 {
+                let (states, wal) = (&states, &wal);
+                s.spawn(move || {
+                    let runtime = tokio::runtime::Builder::new_current_thread()
+                        .build()
+                        .unwrap();
+                    runtime.block_on(async {
+                        for j in 0..16 {
+                            states.tag_add(UserId(i), format!("tag {i} {j}")).unwrap();
+                            wal.flush().await.unwrap();
+                        }
+                    });
+                });
+            }
+        });
+        let records = block_on(wal.store.read_all()).unwrap();
+        let seqs: Vec<u64> = records.iter().map(|record| record.seq).collect();
+        assert_eq!(seqs, Vec::from_iter(0..128));
+    }
+
+    // queues another record whenever one is stored
+    #[derive(Default)]
+    struct RequeuingStore {
+        inner: MemoryAsyncLogStore,
+        on_record: OnceLock<Box<dyn Fn() + Send + Sync>>,
+    }
+
+    impl AsyncLogStore for RequeuingStore {
+        async fn record(&self, record: LogRecord) -> Result<(), String> {
+            if let Some(on_record) = self.on_record.get() {
+                on_record();
+            }
+            self.inner.record(record).await
+        }
+
+        async fn read_all(&self) -> Result<Vec<LogRecord>, String> {
+            self.inner.read_all().await
+        }
+    }
+
+    #[test]
+    fn flush_ships_only_the_records_queued_before_it() {
+        let wal = Arc::new(QueuedLogStore::new(RequeuingStore::default(), 0));
+        let requeue = Arc::downgrade(&wal);
+        let on_record = Box::new(move || {
+            if let Some(wal) = requeue.upgrade() {
+                wal.record(UserId(1), "tag_add", &"more").unwrap();
+            }
+        });
+        wal.store.on_record.set(on_record).ok().unwrap();
+        for tag in ["a", "b"] {
+            wal.record(UserId(1), "tag_add", &tag).unwrap();
+        }
+        block_on(wal.flush()).unwrap();
+        assert_eq!(block_on(wal.store.read_all()).unwrap().len(), 2);
+        assert_eq!(wal.queue.lock().unwrap().0.len(), 2);
+    }
+}
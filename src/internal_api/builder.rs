@@ -0,0 +1,125 @@
+// Pre-seeded `States`, mostly for tests
+//
+// Seeded entities are taken as they are and not recorded in the WAL, like a restored snapshot.
+// Ids are assigned in insertion order, starting from 0 for each kind of entity.
+//
+// `ReleaseBuilder` assembles a whole `Release` for `States::release_add_full`. It does not look
+// at any states, the ids it is given are only checked when the release is added.
+
+use super::States;
+use super::defs::*;
+use super::errors::InternalErr;
+use super::snapshot::StateSnapshot;
+use super::wal::LogStore;
+use std::collections::HashMap;
+
+#[derive(Clone, Debug, Default)]
+pub struct StatesBuilder {
+    artists: Vec<Option<ArtistMetaData>>,
+    releases: Vec<Release>,
+    events: Vec<Event>,
+    tags: Vec<String>,
+    locations: Vec<(LocationId, String)>,
+}
+
+impl StatesBuilder {
+    pub fn new() -> StatesBuilder {
+        StatesBuilder::default()
+    }
+
+    pub fn artist(mut self, artist: ArtistMetaData) -> StatesBuilder {
+        self.artists.push(Some(artist));
+        self
+    }
+
+    pub fn release(mut self, release: Release) -> StatesBuilder {
+        self.releases.push(release);
+        self
+    }
+
+    pub fn event(mut self, event: Event) -> StatesBuilder {
+        self.events.push(event);
+        self
+    }
+
+    pub fn tag(mut self, name: String) -> StatesBuilder {
+        self.tags.push(name);
+        self
+    }
+
+    pub fn location(mut self, id: LocationId, name: String) -> StatesBuilder {
+        self.locations.push((id, name));
+        self
+    }
+
+    // the derived indexes are computed from the seeded entities, dangling references are kept
+    // and can be found with `States::validate`
+    pub fn build<L: LogStore>(self, wal: &L) -> Result<States<'_, L>, InternalErr> {
+        let states = States::from_snapshot(
+            wal,
+            StateSnapshot {
+                last_seq: None,
+                artists: self.artists,
+                releases: self.releases,
+                events: self.events,
+                tags: self.tags,
+                locations: self.locations,
+                group_members: HashMap::new(),
+                artist_discography: HashMap::new(),
+                derived_songs: HashMap::new(),
+            },
+        );
+        states.recompute_group_members()?;
+        states.recompute_discography()?;
+        states.recompute_derived_songs()?;
+        Ok(states)
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct ReleaseBuilder {
+    release: Release,
+}
+
+impl ReleaseBuilder {
+    pub fn new() -> ReleaseBuilder {
+        ReleaseBuilder::default()
+    }
+
+    pub fn title(mut self, title: String) -> ReleaseBuilder {
+        self.release.title = title;
+        self
+    }
+
+    pub fn kind(mut self, kind: ReleaseKind) -> ReleaseBuilder {
+        self.release.release_kind = Some(kind);
+        self
+    }
+
+    // an artist already added is not added again
+    pub fn album_artist(mut self, artist: ArtistId) -> ReleaseBuilder {
+        if !self.release.album_artists.contains(&artist) {
+            self.release.album_artists.push(artist);
+        }
+        self
+    }
+
+    // replaces the song of a track number that is already taken
+    pub fn add_track(mut self, track_num: TrackNum, song: Song) -> ReleaseBuilder {
+        self.release.tracks.insert(track_num, song);
+        self
+    }
+
+    // a tag already added is not added again
+    pub fn tag(mut self, tag: TagId) -> ReleaseBuilder {
+        if !self.release.tags.contains(&tag) {
+            self.release.tags.push(tag);
+        }
+        self
+    }
+
+    // the seq_id is left for `release_add_full` to set
+    pub fn build(self) -> Release {
+        self.release
+    }
+}
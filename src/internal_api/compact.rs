@@ -0,0 +1,138 @@
+// WAL compaction
+//
+// `*_add` payloads carry the whole entity, so the add record of each entity, holding its current
+// value (including tracks and seq_id), is the only record it needs. Every intermediate update is
+// dropped. Removed artists keep their slot so later ids stay stable: they are compacted to an
+// empty `artist_add` followed by `artist_remove`. Derived indexes are never logged.
+//
+// Compacted records are attributed to the user who created the entity, carry the timestamp of
+// the last original record, and are numbered on from the sequence number of the first original
+// record, like a store numbers the records written to it. The compacted log holds every entity,
+// so it can be longer than the original when that starts after a snapshot, and is replayed with
+// `replay` rather than on top of the snapshot.
+
+use super::States;
+use super::defs::*;
+use super::errors::InternalErr;
+use super::operation::{ApiName, Operation};
+use super::wal::{LogRecord, LogStore};
+
+impl<'a, L: LogStore> States<'a, L> {
+    // `records` must be the log these states were built from
+    pub fn compact(&self, records: &[LogRecord]) -> Result<Vec<LogRecord>, InternalErr> {
+        let snapshot = self.snapshot()?;
+        let (Some(first), Some(last)) = (records.first(), records.last()) else {
+            return Ok(Vec::new());
+        };
+        if snapshot.last_seq != Some(last.seq) {
+            return Err(InternalErr::Other(
+                "records do not match the states being compacted".to_owned(),
+            ));
+        }
+        // the records may start after a snapshot, so the last adds belong to the last entities;
+        // entities created before the first record are attributed to the last user
+        let creators = |api_name: ApiName| -> Vec<UserId> {
+            records
+                .iter()
+                .filter(|record| record.api_name == api_name.as_str())
+                .map(|record| record.user)
+                .collect()
+        };
+        let creator = |creators: &[UserId], count: usize, i: usize| {
+            (i + creators.len())
+                .checked_sub(count)
+                .map_or(last.user, |j| creators[j])
+        };
+        let (artist_creators, release_creators, event_creators, tag_creators) = (
+            creators(ApiName::ArtistAdd),
+            creators(ApiName::ReleaseAdd),
+            creators(ApiName::EventAdd),
+            creators(ApiName::TagAdd),
+        );
+        let location_creators = creators(ApiName::LocationAdd);
+
+        let mut compacted = Vec::new();
+        let artist_count = snapshot.artists.len();
+        for (i, artist) in snapshot.artists.into_iter().enumerate() {
+            let user = creator(&artist_creators, artist_count, i);
+            match artist {
+                Some(artist) => compacted.push((user, Operation::ArtistAdd(artist))),
+                None => {
+                    compacted.push((user, Operation::ArtistAdd(ArtistMetaData::default())));
+                    compacted.push((user, Operation::ArtistRemove(ArtistId(i))));
+                }
+            }
+        }
+        let release_count = snapshot.releases.len();
+        for (i, release) in snapshot.releases.into_iter().enumerate() {
+            let user = creator(&release_creators, release_count, i);
+            compacted.push((user, Operation::ReleaseAdd(release)));
+        }
+        let event_count = snapshot.events.len();
+        for (i, event) in snapshot.events.into_iter().enumerate() {
+            let user = creator(&event_creators, event_count, i);
+            compacted.push((user, Operation::EventAdd(event)));
+        }
+        let tag_count = snapshot.tags.len();
+        for (i, tag) in snapshot.tags.into_iter().enumerate() {
+            let user = creator(&tag_creators, tag_count, i);
+            compacted.push((user, Operation::TagAdd(tag)));
+        }
+        let location_count = snapshot.locations.len();
+        for (i, (id, name)) in snapshot.locations.into_iter().enumerate() {
+            let user = creator(&location_creators, location_count, i);
+            compacted.push((user, Operation::LocationAdd { id, name }));
+        }
+
+        compacted
+            .into_iter()
+            .zip(first.seq..)
+            .map(|((user, op), seq)| {
+                Ok(LogRecord {
+                    seq,
+                    timestamp: last.timestamp,
+                    user,
+                    api_name: op.api_name().as_str().to_owned(),
+                    payload: serde_json::to_string(&op.payload())
+                        .map_err(|e| InternalErr::Other(e.to_string()))?,
+                })
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::test_util::populate;
+    use super::super::wal::NaiveLogStore;
+    use super::*;
+
+    #[test]
+    fn compacted_log_replays_to_the_same_states() {
+        let wal = NaiveLogStore::new();
+        let states = States::new(&wal);
+        populate(&states);
+        let records = wal.read_all().unwrap();
+        let compacted = states.compact(&records).unwrap();
+        assert!(compacted.len() < records.len());
+        let replayed = States::replay(&wal, &compacted).unwrap();
+        assert_eq!(replayed.diff_against(&states).unwrap(), Vec::new());
+    }
+
+    #[test]
+    fn compacted_log_after_a_snapshot_can_be_longer() {
+        let wal = NaiveLogStore::new();
+        let states = States::new(&wal);
+        populate(&states);
+        let snapshot = states.snapshot().unwrap();
+        let skipped = wal.read_all().unwrap().len();
+        states.artist_add(UserId(3), "late".to_owned()).unwrap();
+        let records = wal.read_all().unwrap().split_off(skipped);
+        let restored = States::replay_from_snapshot(&wal, snapshot, &records).unwrap();
+        let compacted = restored.compact(&records).unwrap();
+        assert!(compacted.len() > records.len());
+        assert_eq!(compacted[0].seq, records[0].seq);
+        let replayed = States::replay(&wal, &compacted).unwrap();
+        assert_eq!(replayed.diff_against(&states).unwrap(), Vec::new());
+    }
+}
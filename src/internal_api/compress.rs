@@ -0,0 +1,75 @@
+// Compressed WAL payloads
+//
+// `CompressingLogStore` deflates the serialized payload and hands it to the wrapped store as a
+// base64 string, so the wrapped store still logs JSON. The sequence number, timestamp, user and
+// api_name stay in the clear, so records can be filtered without decompressing them.
+
+use super::UserId;
+use super::wal::{LogRecord, LogStore};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use flate2::Compression;
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+use serde::Serialize;
+use std::io::{Read, Write};
+
+#[derive(Debug)]
+pub struct CompressingLogStore<L: LogStore> {
+    inner: L,
+}
+
+impl<L: LogStore> CompressingLogStore<L> {
+    pub fn new(inner: L) -> CompressingLogStore<L> {
+        CompressingLogStore { inner }
+    }
+
+    pub fn into_inner(self) -> L {
+        self.inner
+    }
+}
+
+fn compress(payload: &str) -> Result<String, String> {
+    let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+    encoder
+        .write_all(payload.as_bytes())
+        .map_err(|e| e.to_string())?;
+    Ok(STANDARD.encode(encoder.finish().map_err(|e| e.to_string())?))
+}
+
+fn decompress(payload: &str) -> Result<String, String> {
+    // the wrapped store logged the base64 string as a JSON string
+    let encoded: String = serde_json::from_str(payload).map_err(|e| e.to_string())?;
+    let compressed = STANDARD.decode(encoded).map_err(|e| e.to_string())?;
+    let mut payload = String::new();
+    DeflateDecoder::new(compressed.as_slice())
+        .read_to_string(&mut payload)
+        .map_err(|e| e.to_string())?;
+    Ok(payload)
+}
+
+impl<L: LogStore> LogStore for CompressingLogStore<L> {
+    fn record<T: Serialize>(
+        &self,
+        user: UserId,
+        api_name: &str,
+        payload: &T,
+    ) -> Result<u64, String> {
+        let payload = serde_json::to_string(payload).map_err(|e| e.to_string())?;
+        self.inner.record(user, api_name, &compress(&payload)?)
+    }
+
+    // records with decompressed payloads
+    fn read_all(&self) -> Result<Vec<LogRecord>, String> {
+        self.inner
+            .read_all()?
+            .into_iter()
+            .map(|record| {
+                Ok(LogRecord {
+                    payload: decompress(&record.payload)?,
+                    ..record
+                })
+            })
+            .collect()
+    }
+}
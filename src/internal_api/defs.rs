@@ -1,56 +1,107 @@
+use super::errors::InternalErr;
 use super::hashes::*;
+// ids, kinds and dates, see the `core_types` crate
+pub use core_types::*;
 use macros::DiffFields;
-use serde::{Deserialize, Serialize};
-use serde_with::skip_serializing_none;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde_with::{DeserializeAs, SerializeAs, serde_as, skip_serializing_none};
 use std::collections::HashMap;
+use std::convert::Infallible;
+use std::hash::{Hash, Hasher};
+use std::str::FromStr;
 use ustr::Ustr;
 
-#[derive(Copy, Clone, Debug, PartialOrd, Ord, PartialEq, Eq, Hash, Serialize, Deserialize)]
-pub struct ArtistId(pub usize);
-
-#[derive(Copy, Clone, Debug, PartialOrd, Ord, PartialEq, Eq, Hash, Serialize, Deserialize)]
-pub struct ReleaseId(pub usize);
-
-#[derive(Copy, Clone, Debug, PartialOrd, Ord, PartialEq, Eq, Hash, Serialize, Deserialize)]
-pub struct TagId(pub usize);
-
-#[derive(Copy, Clone, Debug, PartialOrd, Ord, PartialEq, Eq, Hash, Serialize, Deserialize)]
-pub struct EventId(pub usize);
-
-#[derive(Copy, Clone, Debug, PartialOrd, Ord, PartialEq, Eq, Hash, Serialize, Deserialize)]
-pub struct UserId(pub usize);
-
 #[derive(Copy, Clone, Debug, PartialOrd, Ord, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub struct LocalId(pub Ustr);
 
 #[derive(Copy, Clone, Debug, PartialOrd, Ord, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub struct LocationId(pub Ustr);
 
-#[derive(Copy, Clone, Debug, PartialOrd, Ord, PartialEq, Eq, Hash, Serialize, Deserialize)]
-pub struct FileId(pub usize);
+// The tuple constructors take any string, e.g. when deserializing. Input from users should go
+// through `parse`, which only checks the shape of the code, not that it is assigned, and
+// normalizes the case, so "EN-us" and "en-US" are the same key. Only accepted codes are
+// interned.
 
-#[derive(Copy, Clone, Debug, PartialOrd, Ord, PartialEq, Eq, Hash, Serialize, Deserialize)]
-pub struct TrackNum {
-    // 0 if there is no disc, otherwise starts from 1
-    pub disc_num: u16,
-    // also starts from 1...
-    pub track_num: u16,
+impl LocalId {
+    // a BCP 47 language tag: a 2-3 letter ISO 639 language, then subtags of 1-8 letters or
+    // digits, e.g. "en", "zh-Hant-TW", "de-CH-1996"
+    // the 5-8 letter languages BCP 47 also allows are not in use, and would let "english" in
+    pub fn parse(tag: &str) -> Result<LocalId, InternalErr> {
+        let invalid = || InternalErr::MalformedLocalId(tag.to_owned());
+        let mut subtags = tag.trim().split('-');
+        let language = subtags.next().unwrap_or_default();
+        if !matches!(language.len(), 2 | 3) || !language.chars().all(|c| c.is_ascii_alphabetic()) {
+            return Err(invalid());
+        }
+        let mut normalized = language.to_ascii_lowercase();
+        for subtag in subtags {
+            if !(1..=8).contains(&subtag.len())
+                || !subtag.chars().all(|c| c.is_ascii_alphanumeric())
+            {
+                return Err(invalid());
+            }
+            normalized.push('-');
+            let letters = subtag.chars().all(|c| c.is_ascii_alphabetic());
+            match subtag.len() {
+                // script, e.g. "Hant"
+                4 if letters => {
+                    normalized.push_str(&subtag[..1].to_ascii_uppercase());
+                    normalized.push_str(&subtag[1..].to_ascii_lowercase());
+                }
+                // region, e.g. "TW"
+                2 if letters => normalized.push_str(&subtag.to_ascii_uppercase()),
+                _ => normalized.push_str(&subtag.to_ascii_lowercase()),
+            }
+        }
+        Ok(LocalId(Ustr::from(&normalized)))
+    }
+}
+
+impl LocationId {
+    // an ISO 3166-1 alpha-2 country code, optionally with an ISO 3166-2 subdivision, e.g. "JP"
+    // or "JP-13"
+    pub fn parse(code: &str) -> Result<LocationId, InternalErr> {
+        let invalid = || InternalErr::MalformedLocationId(code.to_owned());
+        let code = code.trim();
+        let (country, subdivision) = match code.split_once('-') {
+            Some((country, subdivision)) => (country, Some(subdivision)),
+            None => (code, None),
+        };
+        if country.len() != 2 || !country.chars().all(|c| c.is_ascii_alphabetic()) {
+            return Err(invalid());
+        }
+        if let Some(subdivision) = subdivision
+            && (!(1..=3).contains(&subdivision.len())
+                || !subdivision.chars().all(|c| c.is_ascii_alphanumeric()))
+        {
+            return Err(invalid());
+        }
+        Ok(LocationId(Ustr::from(&code.to_ascii_uppercase())))
+    }
 }
 
+// derived from the file content, so identical files get the same id
 #[derive(Copy, Clone, Debug, PartialOrd, Ord, PartialEq, Eq, Hash, Serialize, Deserialize)]
-pub struct TrackRef {
-    pub release_id: ReleaseId,
-    #[serde(flatten)]
-    pub track_num: TrackNum,
+pub struct ContentFileId(pub Hash128);
+
+impl ContentFileId {
+    pub fn for_bytes(bytes: &[u8]) -> ContentFileId {
+        ContentFileId(file_id_for(bytes))
+    }
 }
 
-#[derive(Copy, Clone, Debug, PartialOrd, PartialEq, Eq, Hash, Serialize, Deserialize)]
-pub enum ArtistKind {
-    Solo,
-    Group,
+// any entity of `States`, by its id
+#[derive(Copy, Clone, Debug, PartialOrd, Ord, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum EntityRef {
+    Artist(ArtistId),
+    Release(ReleaseId),
+    Event(EventId),
+    Track(TrackRef),
+    Tag(TagId),
+    Location(LocationId),
 }
 
-#[derive(Clone, Debug, PartialOrd, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[derive(Clone, Debug, PartialOrd, Ord, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub enum ArtistRole {
     Arranger,
     Vocal,
@@ -58,7 +109,30 @@ pub enum ArtistRole {
     Other(Ustr),
 }
 
-#[derive(Copy, Clone, Debug, PartialOrd, PartialEq, Eq, Hash, Serialize, Deserialize)]
+// for free-text role input: the known roles match case-insensitively, anything else is interned
+// as `Other`
+impl FromStr for ArtistRole {
+    type Err = Infallible;
+
+    fn from_str(s: &str) -> Result<ArtistRole, Infallible> {
+        let s = s.trim();
+        Ok(match s.to_lowercase().as_str() {
+            "arranger" => ArtistRole::Arranger,
+            "vocal" => ArtistRole::Vocal,
+            "lyricist" => ArtistRole::Lyricist,
+            _ => ArtistRole::Other(Ustr::from(s)),
+        })
+    }
+}
+
+impl From<&str> for ArtistRole {
+    fn from(s: &str) -> ArtistRole {
+        let Ok(role) = s.parse();
+        role
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialOrd, Ord, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub enum SongRelationKind {
     Cover,
     Rearrangement,
@@ -67,49 +141,298 @@ pub enum SongRelationKind {
     Other(Ustr),
 }
 
-#[derive(Copy, Clone, Debug, PartialOrd, PartialEq, Eq, Hash, Serialize, Deserialize)]
-pub enum ReleaseKind {
-    Album,
-    Ep,
-    Single,
-    Compilation,
-    Demo,
-    Other,
-}
-
 #[derive(Clone, Debug, PartialOrd, Ord, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub struct StringWithLocal {
     pub local: LocalId,
     pub content: String,
 }
 
-#[derive(Copy, Clone, Debug, PartialOrd, PartialEq, Eq, Hash, Serialize, Deserialize)]
-pub enum DatePrecision {
-    Year,
-    Month,
-    Day,
+// element-level update of a `Vec` field, see `#[vec_diff]`
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum VecOp<T> {
+    Push(T),
+    Insert(usize, T),
+    RemoveAt(usize),
+    Set(usize, T),
 }
 
-#[derive(Copy, Clone, Debug, PartialOrd, PartialEq, Eq, Hash, Serialize, Deserialize)]
-pub struct DateWithPrecision {
-    pub year: u16,
-    pub month: u16,
-    pub day: u16,
-    pub precision: DatePrecision,
+impl<T: Clone + PartialEq> VecOp<T> {
+    pub fn apply(self, v: &mut Vec<T>) -> Result<(), InternalErr> {
+        match self {
+            VecOp::Push(x) => v.push(x),
+            VecOp::Insert(i, x) => {
+                if i > v.len() {
+                    return Err(InternalErr::IndexOutOfBounds(i));
+                }
+                v.insert(i, x);
+            }
+            VecOp::RemoveAt(i) => {
+                if i >= v.len() {
+                    return Err(InternalErr::IndexOutOfBounds(i));
+                }
+                v.remove(i);
+            }
+            VecOp::Set(i, x) => {
+                *v.get_mut(i).ok_or(InternalErr::IndexOutOfBounds(i))? = x;
+            }
+        }
+        Ok(())
+    }
+
+    // whether applying the op leaves `v` as it is, e.g. setting an element to its current value
+    pub fn is_noop(&self, v: &[T]) -> bool {
+        match self {
+            VecOp::Set(i, x) => v.get(*i) == Some(x),
+            VecOp::Push(_) | VecOp::Insert(..) | VecOp::RemoveAt(_) => false,
+        }
+    }
+
+    // the op that undoes this one, given the vector before applying it
+    pub fn invert(&self, v: &[T]) -> Result<VecOp<T>, InternalErr> {
+        let get = |i: usize| v.get(i).cloned().ok_or(InternalErr::IndexOutOfBounds(i));
+        Ok(match self {
+            VecOp::Push(_) => VecOp::RemoveAt(v.len()),
+            VecOp::Insert(i, _) => {
+                if *i > v.len() {
+                    return Err(InternalErr::IndexOutOfBounds(*i));
+                }
+                VecOp::RemoveAt(*i)
+            }
+            VecOp::RemoveAt(i) => VecOp::Insert(*i, get(*i)?),
+            VecOp::Set(i, _) => VecOp::Set(*i, get(*i)?),
+        })
+    }
+
+    // ops that turn `old` into `new` when applied in order
+    pub fn diff(old: &[T], new: &[T]) -> Vec<VecOp<T>> {
+        let mut ops: Vec<_> = old
+            .iter()
+            .zip(new)
+            .enumerate()
+            .filter(|(_, (a, b))| a != b)
+            .map(|(i, (_, b))| VecOp::Set(i, b.clone()))
+            .collect();
+        ops.extend(new.iter().skip(old.len()).cloned().map(VecOp::Push));
+        ops.extend((new.len()..old.len()).rev().map(VecOp::RemoveAt));
+        ops
+    }
+}
+
+// key-level update of a map field, see `#[map_diff]`
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum MapOp<K, V> {
+    Insert(K, V),
+    Remove(K),
+}
+
+// lets `#[map_diff]` name the key and value types of fields declared through a type alias
+pub trait MapField {
+    type Key;
+    type Value;
+}
+
+impl<K, V> MapField for HashMap<K, V> {
+    type Key = K;
+    type Value = V;
+}
+
+impl<K: Clone + Ord + Eq + Hash, V: Clone + PartialEq> MapOp<K, V> {
+    pub fn key(&self) -> &K {
+        match self {
+            MapOp::Insert(k, _) | MapOp::Remove(k) => k,
+        }
+    }
+
+    pub fn apply(self, map: &mut HashMap<K, V>) {
+        match self {
+            MapOp::Insert(k, v) => {
+                map.insert(k, v);
+            }
+            MapOp::Remove(k) => {
+                map.remove(&k);
+            }
+        }
+    }
+
+    pub fn is_noop(&self, map: &HashMap<K, V>) -> bool {
+        match self {
+            MapOp::Insert(k, v) => map.get(k) == Some(v),
+            MapOp::Remove(k) => !map.contains_key(k),
+        }
+    }
+
+    // the op that undoes this one, given the map before applying it
+    pub fn invert(&self, map: &HashMap<K, V>) -> MapOp<K, V> {
+        let k = self.key();
+        match map.get(k) {
+            Some(v) => MapOp::Insert(k.clone(), v.clone()),
+            None => MapOp::Remove(k.clone()),
+        }
+    }
+
+    // ops that turn `old` into `new`, ordered by key so the output is deterministic
+    pub fn diff(old: &HashMap<K, V>, new: &HashMap<K, V>) -> Vec<MapOp<K, V>> {
+        let mut ops: Vec<_> = old
+            .keys()
+            .filter(|k| !new.contains_key(k))
+            .map(|k| MapOp::Remove(k.clone()))
+            .chain(
+                new.iter()
+                    .filter(|(k, v)| old.get(k) != Some(v))
+                    .map(|(k, v)| MapOp::Insert(k.clone(), v.clone())),
+            )
+            .collect();
+        ops.sort_by(|a, b| a.key().cmp(b.key()));
+        ops
+    }
 }
 
-#[derive(Copy, Clone, Debug, PartialOrd, PartialEq, Eq, Hash, Serialize, Deserialize)]
-pub struct Birthday {
-    pub month: u16,
-    pub day: u16,
+// entities carrying a seq_id, updated sequentially through their `DiffFields` diff
+pub trait Versioned {
+    type Diff: Clone + Hash + Serialize;
+
+    fn seq_id(&self) -> Hash128;
+    fn seq_id_mut(&mut self) -> &mut Hash128;
+    fn invert_diff(&self, diff: &Self::Diff) -> Result<Self::Diff, InternalErr>;
+    fn apply_diff(&mut self, diff: Self::Diff) -> Result<(), InternalErr>;
+    fn is_noop(&self, diff: &Self::Diff) -> bool;
 }
 
+macro_rules! impl_versioned {
+    ($owner:ty, $diff:ty, $invert:ident, $apply:ident) => {
+        impl Versioned for $owner {
+            type Diff = $diff;
+
+            fn seq_id(&self) -> Hash128 {
+                self.seq_id
+            }
+
+            fn seq_id_mut(&mut self) -> &mut Hash128 {
+                &mut self.seq_id
+            }
+
+            fn invert_diff(&self, diff: &$diff) -> Result<$diff, InternalErr> {
+                $invert(self, diff)
+            }
+
+            fn apply_diff(&mut self, diff: $diff) -> Result<(), InternalErr> {
+                $apply(self, diff)
+            }
+
+            fn is_noop(&self, diff: &$diff) -> bool {
+                diff.is_noop(self)
+            }
+        }
+    };
+}
+
+impl_versioned!(
+    ArtistMetaData,
+    ArtistMetaDataDiff,
+    invert_artist_meta_data_diff,
+    apply_artist_meta_data_diff
+);
+impl_versioned!(
+    Release,
+    ReleaseDiff,
+    invert_release_diff,
+    apply_release_diff
+);
+impl_versioned!(Event, EventDiff, invert_event_diff, apply_event_diff);
+impl_versioned!(Song, SongDiff, invert_song_diff, apply_song_diff);
+
 pub type LocalizedDocuments = HashMap<LocalId, FileId>;
 pub type LocalizedStrings = HashMap<LocalId, String>;
 
+// Map fields are written in key order, so equal entities always serialize to the same JSON, in
+// the WAL as well as in exports. Reading accepts any order.
+
+// a map, as a JSON object
+pub struct SortedMap;
+
+impl<K: Ord + Serialize, V: Serialize> SerializeAs<HashMap<K, V>> for SortedMap {
+    fn serialize_as<S: Serializer>(map: &HashMap<K, V>, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut entries: Vec<_> = map.iter().collect();
+        entries.sort_by(|a, b| a.0.cmp(b.0));
+        serializer.collect_map(entries)
+    }
+}
+
+impl<'de, K, V> DeserializeAs<'de, HashMap<K, V>> for SortedMap
+where
+    K: Deserialize<'de> + Eq + Hash,
+    V: Deserialize<'de>,
+{
+    fn deserialize_as<D: Deserializer<'de>>(deserializer: D) -> Result<HashMap<K, V>, D::Error> {
+        HashMap::deserialize(deserializer)
+    }
+}
+
+// a map, as a list of pairs, for keys that are not strings in JSON
+pub struct SortedPairs;
+
+impl<K: Ord + Serialize, V: Serialize> SerializeAs<HashMap<K, V>> for SortedPairs {
+    fn serialize_as<S: Serializer>(map: &HashMap<K, V>, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut entries: Vec<_> = map.iter().collect();
+        entries.sort_by(|a, b| a.0.cmp(b.0));
+        serializer.collect_seq(entries)
+    }
+}
+
+impl<'de, K, V> DeserializeAs<'de, HashMap<K, V>> for SortedPairs
+where
+    K: Deserialize<'de> + Eq + Hash,
+    V: Deserialize<'de>,
+{
+    fn deserialize_as<D: Deserializer<'de>>(deserializer: D) -> Result<HashMap<K, V>, D::Error> {
+        Ok(Vec::<(K, V)>::deserialize(deserializer)?
+            .into_iter()
+            .collect())
+    }
+}
+
+// the entry of the first preferred locale present in the map, or `None` if there is none
+// (in particular for an empty preference list, rather than an arbitrary entry)
+pub fn localized_get<'a>(map: &'a LocalizedStrings, preferred: &[LocalId]) -> Option<&'a str> {
+    preferred
+        .iter()
+        .find_map(|local| map.get(local))
+        .map(String::as_str)
+}
+
+pub fn localized_document(map: &LocalizedDocuments, preferred: &[LocalId]) -> Option<FileId> {
+    preferred.iter().find_map(|local| map.get(local)).copied()
+}
+
+// Aliases are stored as `Vec<StringWithLocal>`, which keeps their order and allows several
+// aliases in one locale. The map form is for lookups: it keeps the first alias of each locale.
+pub fn to_localized_map(aliases: &[StringWithLocal]) -> LocalizedStrings {
+    let mut map = LocalizedStrings::new();
+    for alias in aliases.iter() {
+        map.entry(alias.local)
+            .or_insert_with(|| alias.content.clone());
+    }
+    map
+}
+
+// in locale order, since the map has no order of its own
+pub fn from_localized_map(map: &LocalizedStrings) -> Vec<StringWithLocal> {
+    let mut aliases: Vec<_> = map
+        .iter()
+        .map(|(local, content)| StringWithLocal {
+            local: *local,
+            content: content.clone(),
+        })
+        .collect();
+    aliases.sort();
+    aliases
+}
+
+#[serde_as]
 #[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub struct Image {
     pub id: FileId,
+    #[serde_as(as = "SortedMap")]
     pub descriptions: LocalizedStrings,
 }
 
@@ -130,9 +453,11 @@ pub struct ArtistMembership {
 }
 
 #[skip_serializing_none]
+#[serde_as]
 #[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize, DiffFields, Default)]
 pub struct ArtistMetaData {
     pub name: String,
+    #[vec_diff]
     pub aliases: Vec<StringWithLocal>,
     pub kind: Option<ArtistKind>,
     pub start_loc: Option<LocationId>,
@@ -141,6 +466,7 @@ pub struct ArtistMetaData {
     pub end_date: Option<DateWithPrecision>,
     pub birthday: Option<Birthday>,
     pub birthyear: Option<u16>,
+    #[vec_diff]
     pub urls: Vec<Url>,
 
     #[skip_diff]
@@ -149,13 +475,14 @@ pub struct ArtistMetaData {
     pub profile_image: Option<Image>,
     #[skip_diff]
     pub memberships: Vec<ArtistMembership>,
-    #[skip_diff]
     pub tags: Vec<TagId>,
-    #[skip_diff]
+    #[map_diff]
+    #[serde_as(as = "SortedMap")]
     pub descriptions: LocalizedDocuments,
 }
 
-#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize, DiffFields)]
+#[serde_as]
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize, DiffFields, Default)]
 pub struct Song {
     pub title: String,
     pub artists: Vec<ArtistId>,
@@ -165,21 +492,26 @@ pub struct Song {
     pub duration_s: Option<u32>,
 
     #[skip_diff]
+    pub seq_id: Hash128,
     pub tags: Vec<TagId>,
-    #[skip_diff]
+    #[map_diff]
+    #[serde_as(as = "SortedMap")]
     pub localized_titles: LocalizedStrings,
     #[skip_diff]
+    #[serde_as(as = "SortedMap")]
     pub lyrics: LocalizedDocuments,
 }
 
 // for query, also return artist -> name mapping, and simple song metadata
 #[skip_serializing_none]
+#[serde_as]
 #[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize, DiffFields, Default)]
 pub struct Release {
     pub title: String,
     pub release_kind: Option<ReleaseKind>,
     pub catalog_num: Option<String>,
     pub album_artists: Vec<ArtistId>,
+    // it has to be one of `images`, see `set_cover_art`
     pub cover_art: Option<Image>,
     pub credits: Vec<(ArtistId, ArtistRole)>,
     pub disc_names: Vec<String>,
@@ -189,19 +521,49 @@ pub struct Release {
 
     #[skip_diff]
     pub seq_id: Hash128,
-    #[skip_diff]
+    #[map_diff]
+    #[serde_as(as = "SortedMap")]
     pub localized_titles: LocalizedStrings,
+    // JSON map keys have to be strings, so tracks are serialized as a list of pairs
     #[skip_diff]
+    #[serde_as(as = "SortedPairs")]
     pub tracks: HashMap<TrackNum, Song>,
-    #[skip_diff]
     pub tags: Vec<TagId>,
     #[skip_diff]
     pub images: Vec<Image>,
-    #[skip_diff]
+    #[map_diff]
+    #[serde_as(as = "SortedMap")]
     pub descriptions: LocalizedDocuments,
 }
 
+// the canonical order of credits: sorted by artist, then role, without repeated pairs
+// an artist with several roles keeps one entry per role
+pub fn normalize_credits(credits: &mut Vec<(ArtistId, ArtistRole)>) {
+    credits.sort();
+    credits.dedup();
+}
+
+impl Song {
+    pub fn normalize_credits(&mut self) {
+        normalize_credits(&mut self.credits);
+    }
+}
+
+impl Release {
+    // tracks in play order, see `TrackNum::sort_key`
+    pub fn tracks_ordered(&self) -> Vec<(TrackNum, &Song)> {
+        let mut tracks: Vec<_> = self
+            .tracks
+            .iter()
+            .map(|(track_num, song)| (*track_num, song))
+            .collect();
+        tracks.sort_by_key(|(track_num, _)| track_num.sort_key());
+        tracks
+    }
+}
+
 #[skip_serializing_none]
+#[serde_as]
 #[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize, DiffFields, Default)]
 pub struct Event {
     pub name: String,
@@ -213,8 +575,243 @@ pub struct Event {
 
     #[skip_diff]
     pub seq_id: Hash128,
-    #[skip_diff]
+    #[map_diff]
+    #[serde_as(as = "SortedMap")]
     pub localized_names: LocalizedStrings,
-    #[skip_diff]
+    #[map_diff]
+    #[serde_as(as = "SortedMap")]
     pub descriptions: LocalizedDocuments,
 }
+
+impl CanonicalHash for Image {
+    fn canonical_hash<H: Hasher>(&self, state: &mut H) {
+        let Image { id, descriptions } = self;
+        id.hash(state);
+        hash_map_sorted(descriptions, state, Hash::hash);
+    }
+}
+
+// for `ReleaseDiff::CoverArt`, the descriptions are a map so they are hashed in key order
+impl Hash for Image {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.canonical_hash(state);
+    }
+}
+
+impl CanonicalHash for ArtistMetaData {
+    fn canonical_hash<H: Hasher>(&self, state: &mut H) {
+        let ArtistMetaData {
+            name,
+            aliases,
+            kind,
+            start_loc,
+            current_loc,
+            start_date,
+            end_date,
+            birthday,
+            birthyear,
+            urls,
+            seq_id: _,
+            profile_image,
+            memberships,
+            tags,
+            descriptions,
+        } = self;
+        (name, aliases, kind, start_loc, current_loc).hash(state);
+        (start_date, end_date, birthday, birthyear, urls).hash(state);
+        profile_image.canonical_hash(state);
+        memberships.hash(state);
+        hash_unordered(tags, state);
+        hash_map_sorted(descriptions, state, Hash::hash);
+    }
+}
+
+impl CanonicalHash for Song {
+    fn canonical_hash<H: Hasher>(&self, state: &mut H) {
+        let Song {
+            title,
+            artists,
+            credits,
+            language,
+            originals,
+            duration_s,
+            seq_id: _,
+            tags,
+            localized_titles,
+            lyrics,
+        } = self;
+        (title, artists, credits, language, originals, duration_s).hash(state);
+        hash_unordered(tags, state);
+        hash_map_sorted(localized_titles, state, Hash::hash);
+        hash_map_sorted(lyrics, state, Hash::hash);
+    }
+}
+
+impl CanonicalHash for Release {
+    fn canonical_hash<H: Hasher>(&self, state: &mut H) {
+        let Release {
+            title,
+            release_kind,
+            catalog_num,
+            album_artists,
+            cover_art,
+            credits,
+            disc_names,
+            event,
+            release_date,
+            urls,
+            seq_id: _,
+            localized_titles,
+            tracks,
+            tags,
+            images,
+            descriptions,
+        } = self;
+        (title, release_kind, catalog_num, album_artists).hash(state);
+        cover_art.canonical_hash(state);
+        (credits, disc_names, event, release_date, urls).hash(state);
+        hash_map_sorted(localized_titles, state, Hash::hash);
+        hash_map_sorted(tracks, state, Song::canonical_hash);
+        hash_unordered(tags, state);
+        images.canonical_hash(state);
+        hash_map_sorted(descriptions, state, Hash::hash);
+    }
+}
+
+impl CanonicalHash for Event {
+    fn canonical_hash<H: Hasher>(&self, state: &mut H) {
+        let Event {
+            name,
+            location,
+            address,
+            start_date,
+            end_date,
+            urls,
+            seq_id: _,
+            localized_names,
+            descriptions,
+        } = self;
+        (name, location, address, start_date, end_date, urls).hash(state);
+        hash_map_sorted(localized_names, state, Hash::hash);
+        hash_map_sorted(descriptions, state, Hash::hash);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn vec_op_out_of_bounds() {
+        let mut v = vec![1, 2];
+        for op in [VecOp::Insert(3, 0), VecOp::RemoveAt(2), VecOp::Set(2, 0)] {
+            let err = match op {
+                VecOp::Insert(i, _) | VecOp::RemoveAt(i) | VecOp::Set(i, _) => {
+                    Err(InternalErr::IndexOutOfBounds(i))
+                }
+                VecOp::Push(_) => unreachable!(),
+            };
+            assert_eq!(op.invert(&v).map(|_| ()), err);
+            assert_eq!(op.apply(&mut v), err);
+            assert_eq!(v, [1, 2]);
+        }
+        // inserting at the end is in bounds
+        VecOp::Insert(2, 3).apply(&mut v).unwrap();
+        assert_eq!(v, [1, 2, 3]);
+    }
+
+    #[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize, DiffFields)]
+    pub struct Renamed {
+        #[diff_rename("Title")]
+        name: String,
+        count: u32,
+    }
+
+    #[test]
+    fn renamed_variants_keep_the_field_name() {
+        let diff = RenamedDiff::Title("title".to_owned());
+        assert_eq!(diff.field_name(), "name");
+        assert_eq!(RenamedDiff::FIELD_NAMES, ["name", "count"]);
+        assert_eq!(
+            serde_json::to_string(&diff).unwrap(),
+            r#"{"Title":"title"}"#
+        );
+        let mut renamed = Renamed {
+            name: "name".to_owned(),
+            count: 0,
+        };
+        let old = renamed.clone();
+        renamed.apply_diff(diff.clone()).unwrap();
+        assert_eq!(renamed.name, "title");
+        assert_eq!(renamed_diffs(&old, &renamed), vec![diff]);
+    }
+
+    #[test]
+    fn apply_diff_method_matches_the_function() {
+        let diffs = [
+            ArtistMetaDataDiff::Name("name".to_owned()),
+            ArtistMetaDataDiff::Birthyear(Some(2000)),
+            ArtistMetaDataDiff::Aliases(VecOp::RemoveAt(0)),
+        ];
+        let mut by_method = ArtistMetaData::default();
+        let mut by_function = ArtistMetaData::default();
+        for diff in diffs {
+            assert_eq!(
+                by_method.apply_diff(diff.clone()),
+                apply_artist_meta_data_diff(&mut by_function, diff)
+            );
+            assert_eq!(by_method, by_function);
+        }
+        assert_eq!(by_method.name, "name");
+        assert_eq!(
+            by_method.apply_diff(ArtistMetaDataDiff::Aliases(VecOp::RemoveAt(0))),
+            Err(InternalErr::IndexOutOfBounds(0))
+        );
+    }
+
+    #[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize, DiffFields)]
+    #[diff(only(name, aliases))]
+    pub struct Allowlisted {
+        name: String,
+        #[vec_diff]
+        aliases: Vec<String>,
+        count: u32,
+    }
+
+    #[test]
+    fn allowlisted_fields_alone_get_variants() {
+        assert_eq!(AllowlistedDiff::FIELD_NAMES, ["name", "aliases"]);
+        let old = Allowlisted {
+            name: "name".to_owned(),
+            aliases: vec![],
+            count: 0,
+        };
+        let new = Allowlisted {
+            name: "name".to_owned(),
+            aliases: vec!["alias".to_owned()],
+            count: 1,
+        };
+        // the field attributes still apply, and other fields are not compared
+        assert_eq!(
+            allowlisted_diffs(&old, &new),
+            vec![AllowlistedDiff::Aliases(VecOp::Push("alias".to_owned()))]
+        );
+    }
+
+    #[test]
+    fn rejected_codes_are_not_interned() {
+        let tag = "not a locale tag";
+        assert_eq!(
+            LocalId::parse(tag),
+            Err(InternalErr::MalformedLocalId(tag.to_owned()))
+        );
+        let code = "not-a-location-code";
+        assert_eq!(
+            LocationId::parse(code),
+            Err(InternalErr::MalformedLocationId(code.to_owned()))
+        );
+        assert_eq!(ustr::existing_ustr(tag), None);
+        assert_eq!(ustr::existing_ustr(code), None);
+        assert_eq!(LocalId::parse(" EN-us").unwrap().0, "en-US");
+    }
+}
@@ -0,0 +1,323 @@
+// Derived indexes
+//
+// These maps are computed from the entities in `States` and are never recorded in the WAL.
+// Every mutation updates only the entries it affects, leaving them as a recompute would build
+// them: sorted, deduplicated, and without empty entries. A track can only be given originals
+// that exist, so every edge is indexed when it is added. `artist_merge` rewrites references all
+// over the catalog, so it recomputes all maps instead. Every recompute rebuilds the map from
+// scratch, so it is idempotent and serves as the repair path. Replay runs all of them after
+// applying the records.
+
+use super::States;
+use super::defs::*;
+use super::errors::{InternalErr, LockName};
+use super::wal::LogStore;
+use std::collections::{HashMap, HashSet};
+use std::hash::Hash;
+
+pub(super) fn index<K: Hash + Eq, V: Ord>(map: &mut HashMap<K, Vec<V>>, key: K, value: V) {
+    let values = map.entry(key).or_default();
+    if let Err(i) = values.binary_search(&value) {
+        values.insert(i, value);
+    }
+}
+
+pub(super) fn unindex<K: Hash + Eq, V: Ord>(map: &mut HashMap<K, Vec<V>>, key: K, value: &V) {
+    if let Some(values) = map.get_mut(&key) {
+        if let Ok(i) = values.binary_search(value) {
+            values.remove(i);
+        }
+        if values.is_empty() {
+            map.remove(&key);
+        }
+    }
+}
+
+// `member`'s memberships change from `before` to `after`
+// the caller locks `group_members` before the artist, see `check_kind_change`
+pub(super) fn index_member(
+    group_members: &mut HashMap<ArtistId, Vec<ArtistId>>,
+    member: ArtistId,
+    before: &[ArtistMembership],
+    after: &[ArtistMembership],
+) {
+    for membership in before.iter() {
+        let group = membership.group_id;
+        if !after.iter().any(|membership| membership.group_id == group) {
+            unindex(group_members, group, &member);
+        }
+    }
+    for membership in after.iter() {
+        index(group_members, membership.group_id, member);
+    }
+}
+
+// the artists whose discography has a track with these artists on a release with these album
+// artists
+pub(super) fn track_artists(artists: &[ArtistId], album_artists: &[ArtistId]) -> HashSet<ArtistId> {
+    artists
+        .iter()
+        .chain(album_artists.iter())
+        .copied()
+        .collect()
+}
+
+impl<'a, L: LogStore> States<'a, L> {
+    pub fn recompute_group_members(&self) -> Result<(), InternalErr> {
+        let mut group_members: HashMap<ArtistId, Vec<ArtistId>> = HashMap::new();
+        let artists = self.artists.read().named("artists")?;
+        for (i, artist) in artists.iter().enumerate() {
+            let artist = artist.read().named("artist")?;
+            let Some(artist) = artist.as_ref() else {
+                continue;
+            };
+            for membership in artist.memberships.iter() {
+                group_members
+                    .entry(membership.group_id)
+                    .or_default()
+                    .push(ArtistId(i));
+            }
+        }
+        for members in group_members.values_mut() {
+            members.sort();
+            members.dedup();
+        }
+        *self.group_members.write().named("group_members")? = group_members;
+        Ok(())
+    }
+
+    // tracks performed by each artist, either on the track itself or as an album artist
+    pub fn recompute_discography(&self) -> Result<(), InternalErr> {
+        let mut discography: HashMap<ArtistId, Vec<TrackRef>> = HashMap::new();
+        let releases = self.releases.read().named("releases")?;
+        for (i, release) in releases.iter().enumerate() {
+            let release = release.read().named("release")?;
+            for (track_num, song) in release.tracks.iter() {
+                let track = TrackRef {
+                    release_id: ReleaseId(i),
+                    track_num: *track_num,
+                };
+                for artist in song.artists.iter().chain(release.album_artists.iter()) {
+                    discography.entry(*artist).or_default().push(track);
+                }
+            }
+        }
+        // TrackRef orders by release id, then disc and track number
+        for tracks in discography.values_mut() {
+            tracks.sort();
+            tracks.dedup();
+        }
+        *self
+            .artist_discography
+            .write()
+            .named("artist_discography")? = discography;
+        Ok(())
+    }
+
+    // reverse edges of `Song::originals`, keyed by the original track
+    // relations pointing at a missing track are skipped and returned as `InvalidTrackRef`
+    pub fn recompute_derived_songs(&self) -> Result<Vec<InternalErr>, InternalErr> {
+        let mut tracks = HashSet::new();
+        let mut edges = Vec::new();
+        {
+            let releases = self.releases.read().named("releases")?;
+            for (i, release) in releases.iter().enumerate() {
+                for (track_num, song) in release.read().named("release")?.tracks.iter() {
+                    let track = TrackRef {
+                        release_id: ReleaseId(i),
+                        track_num: *track_num,
+                    };
+                    tracks.insert(track);
+                    for (original, kind) in song.originals.iter() {
+                        edges.push((*original, track, *kind));
+                    }
+                }
+            }
+        }
+        edges.sort();
+        edges.dedup();
+
+        let mut derived_songs: HashMap<TrackRef, Vec<(TrackRef, SongRelationKind)>> =
+            HashMap::new();
+        let mut dangling = Vec::new();
+        for (original, track, kind) in edges {
+            if tracks.contains(&original) {
+                derived_songs
+                    .entry(original)
+                    .or_default()
+                    .push((track, kind));
+            } else {
+                dangling.push(InternalErr::InvalidTrackRef(original));
+            }
+        }
+        *self.derived_songs.write().named("derived_songs")? = derived_songs;
+        Ok(dangling)
+    }
+
+    // the tracks that exist, looked up one release at a time, so this must not be called while
+    // holding an entity lock; tracks are never removed, so the result stays true
+    pub(super) fn existing_tracks(
+        &self,
+        tracks: impl Iterator<Item = TrackRef>,
+    ) -> Result<HashSet<TrackRef>, InternalErr> {
+        let releases = self.releases.read().named("releases")?;
+        let mut found = HashSet::new();
+        for track in tracks {
+            if let Some(release) = releases.get(track.release_id.0)
+                && release
+                    .read()
+                    .named("release")?
+                    .tracks
+                    .contains_key(&track.track_num)
+            {
+                found.insert(track);
+            }
+        }
+        Ok(found)
+    }
+
+    // moves `track` from the discography of the artists only in `before` to the artists only in
+    // `after`, see `track_artists`
+    pub(super) fn index_track_artists(
+        &self,
+        track: TrackRef,
+        before: &HashSet<ArtistId>,
+        after: &HashSet<ArtistId>,
+    ) -> Result<(), InternalErr> {
+        let mut discography = self
+            .artist_discography
+            .write()
+            .named("artist_discography")?;
+        for artist in before.difference(after) {
+            unindex(&mut discography, *artist, &track);
+        }
+        for artist in after.difference(before) {
+            index(&mut discography, *artist, track);
+        }
+        Ok(())
+    }
+
+    // updates the reverse edges of `track` after its originals changed from `before` to `after`
+    pub(super) fn index_originals(
+        &self,
+        track: TrackRef,
+        before: &[(TrackRef, SongRelationKind)],
+        after: &[(TrackRef, SongRelationKind)],
+    ) -> Result<(), InternalErr> {
+        let before: HashSet<_> = before.iter().copied().collect();
+        let after: HashSet<_> = after.iter().copied().collect();
+        let mut derived_songs = self.derived_songs.write().named("derived_songs")?;
+        for (original, kind) in before.difference(&after) {
+            unindex(&mut derived_songs, *original, &(track, *kind));
+        }
+        for (original, kind) in after.difference(&before) {
+            index(&mut derived_songs, *original, (track, *kind));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::wal::NaiveLogStore;
+    use super::*;
+
+    fn track(release_id: ReleaseId, track_num: u16) -> TrackRef {
+        TrackRef {
+            release_id,
+            track_num: TrackNum {
+                disc_num: 0,
+                track_num,
+            },
+        }
+    }
+
+    type Indexes = (
+        HashMap<ArtistId, Vec<ArtistId>>,
+        HashMap<ArtistId, Vec<TrackRef>>,
+        HashMap<TrackRef, Vec<(TrackRef, SongRelationKind)>>,
+    );
+
+    fn indexes<L: LogStore>(states: &States<'_, L>) -> Indexes {
+        let snapshot = states.snapshot().unwrap();
+        (
+            snapshot.group_members,
+            snapshot.artist_discography,
+            snapshot.derived_songs,
+        )
+    }
+
+    #[test]
+    fn incremental_indexes_match_a_recompute() {
+        let wal = NaiveLogStore::new();
+        let states = States::new(&wal);
+        let user = UserId(1);
+        let group = states.artist_add(user, "group".to_owned()).unwrap();
+        let seq_id = states.get_artist(group).unwrap().seq_id;
+        let diff = ArtistMetaDataDiff::Kind(Some(ArtistKind::Group));
+        states
+            .artist_metadata_update(user, group, diff, seq_id, true)
+            .unwrap();
+        let members: Vec<ArtistId> = (0..3)
+            .map(|i| states.artist_add(user, format!("member {i}")).unwrap())
+            .collect();
+        for member in members.iter().rev() {
+            let membership = ArtistMembership {
+                group_id: group,
+                role: ArtistRole::Vocal,
+                start_date: None,
+                end_date: None,
+            };
+            states.membership_add(user, *member, membership).unwrap();
+        }
+        states.membership_remove(user, members[1], group).unwrap();
+
+        let release = states.release_add(user, "release".to_owned()).unwrap();
+        for n in 1..=3 {
+            let track = track(release, n);
+            states
+                .track_add(user, release, track.track_num, format!("track {n}"))
+                .unwrap();
+            let seq_id = states.resolve_track(track).unwrap().seq_id;
+            let diff = SongDiff::Artists(vec![members[n as usize - 1], group]);
+            states
+                .track_update(user, release, track.track_num, diff, seq_id, true)
+                .unwrap();
+        }
+        for (derived, kind) in [(2, SongRelationKind::Cover), (3, SongRelationKind::Remix)] {
+            states
+                .song_relation_add(user, track(release, derived), track(release, 1), kind)
+                .unwrap();
+        }
+        // a relation to a track added later is rejected until the track exists
+        let (later, first) = (track(release, 4), track(release, 1));
+        let originals = || SongDiff::Originals(vec![(later, SongRelationKind::Remix)]);
+        let seq_id = states.resolve_track(first).unwrap().seq_id;
+        assert_eq!(
+            states.track_update(user, release, first.track_num, originals(), seq_id, true),
+            Err(InternalErr::InvalidTrackRef(later))
+        );
+        assert_eq!(
+            states.song_relation_add(user, first, later, SongRelationKind::Remix),
+            Err(InternalErr::InvalidTrackRef(later))
+        );
+        states
+            .track_add(user, release, later.track_num, "later".to_owned())
+            .unwrap();
+        states
+            .track_update(user, release, first.track_num, originals(), seq_id, true)
+            .unwrap();
+        states.artist_remove(user, members[2]).unwrap();
+
+        let incremental = indexes(&states);
+        assert_eq!(
+            incremental.2[&later],
+            vec![(first, SongRelationKind::Remix)]
+        );
+        assert_eq!(incremental.0[&group], vec![members[0]]);
+        states.recompute_group_members().unwrap();
+        states.recompute_discography().unwrap();
+        assert!(states.recompute_derived_songs().unwrap().is_empty());
+        assert_eq!(incremental, indexes(&states));
+    }
+}
@@ -0,0 +1,220 @@
+// Differences between two `States`, e.g. a replay and the snapshot it should match
+//
+// Both sides are compared through a snapshot each, so neither is locked while the other is.
+// Fields with a diff variant are reported by `field_name` of their diffs, the `#[skip_diff]`
+// fields are compared one by one, and the tracks of a release are compared as entities of their
+// own. Derived indexes are left out, they follow from the entities.
+
+use super::States;
+use super::defs::*;
+use super::errors::InternalErr;
+use super::snapshot::StateSnapshot;
+use super::wal::LogStore;
+use std::collections::BTreeSet;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Divergence {
+    // exists, and is not removed, only in the states `diff_against` is called on
+    OnlyInSelf(EntityRef),
+    OnlyInOther(EntityRef),
+    // exists in both, with these fields differing: the diffed fields in declaration order, then
+    // the `#[skip_diff]` ones
+    Fields {
+        entity: EntityRef,
+        fields: Vec<&'static str>,
+    },
+}
+
+// the distinct field names of `diffs`, in the order they first appear
+fn field_names<D>(diffs: Vec<D>, field_name: impl Fn(&D) -> &'static str) -> Vec<&'static str> {
+    let mut fields = Vec::new();
+    for diff in diffs.iter() {
+        let field = field_name(diff);
+        if !fields.contains(&field) {
+            fields.push(field);
+        }
+    }
+    fields
+}
+
+// `skip` are the `#[skip_diff]` fields as `(name, differs)`
+fn push_fields(
+    divergences: &mut Vec<Divergence>,
+    entity: EntityRef,
+    mut fields: Vec<&'static str>,
+    skip: &[(&'static str, bool)],
+) {
+    fields.extend(
+        skip.iter()
+            .filter(|(_, differs)| *differs)
+            .map(|(name, _)| *name),
+    );
+    if !fields.is_empty() {
+        divergences.push(Divergence::Fields { entity, fields });
+    }
+}
+
+// entities with the same index are the same entity, `None` is a removed or missing one
+fn compare_by_id<'t, T: 't>(
+    divergences: &mut Vec<Divergence>,
+    ours: impl Iterator<Item = Option<&'t T>>,
+    theirs: impl Iterator<Item = Option<&'t T>>,
+    entity: impl Fn(usize) -> EntityRef,
+    mut compare: impl FnMut(&mut Vec<Divergence>, EntityRef, &T, &T),
+) {
+    let (ours, theirs): (Vec<_>, Vec<_>) = (ours.collect(), theirs.collect());
+    for i in 0..ours.len().max(theirs.len()) {
+        let entity = entity(i);
+        match (
+            ours.get(i).copied().flatten(),
+            theirs.get(i).copied().flatten(),
+        ) {
+            (Some(ours), Some(theirs)) => compare(divergences, entity, ours, theirs),
+            (Some(_), None) => divergences.push(Divergence::OnlyInSelf(entity)),
+            (None, Some(_)) => divergences.push(Divergence::OnlyInOther(entity)),
+            (None, None) => {}
+        }
+    }
+}
+
+fn compare_songs(divergences: &mut Vec<Divergence>, entity: EntityRef, ours: &Song, theirs: &Song) {
+    let fields = field_names(song_diffs(ours, theirs), SongDiff::field_name);
+    let skip = [
+        ("seq_id", ours.seq_id != theirs.seq_id),
+        ("lyrics", ours.lyrics != theirs.lyrics),
+    ];
+    push_fields(divergences, entity, fields, &skip);
+}
+
+fn compare_releases(
+    divergences: &mut Vec<Divergence>,
+    entity: EntityRef,
+    ours: &Release,
+    theirs: &Release,
+) {
+    let fields = field_names(release_diffs(ours, theirs), ReleaseDiff::field_name);
+    let skip = [
+        ("cover_art", ours.cover_art != theirs.cover_art),
+        ("seq_id", ours.seq_id != theirs.seq_id),
+        ("images", ours.images != theirs.images),
+    ];
+    push_fields(divergences, entity, fields, &skip);
+
+    let EntityRef::Release(release_id) = entity else {
+        return;
+    };
+    let track_nums: BTreeSet<TrackNum> = ours
+        .tracks
+        .keys()
+        .chain(theirs.tracks.keys())
+        .copied()
+        .collect();
+    for track_num in track_nums {
+        let track = EntityRef::Track(TrackRef {
+            release_id,
+            track_num,
+        });
+        match (ours.tracks.get(&track_num), theirs.tracks.get(&track_num)) {
+            (Some(ours), Some(theirs)) => compare_songs(divergences, track, ours, theirs),
+            (Some(_), None) => divergences.push(Divergence::OnlyInSelf(track)),
+            (None, Some(_)) => divergences.push(Divergence::OnlyInOther(track)),
+            (None, None) => {}
+        }
+    }
+}
+
+// artists, then releases with their tracks, then events, tags and locations, each in id order
+pub fn snapshot_divergences(ours: &StateSnapshot, theirs: &StateSnapshot) -> Vec<Divergence> {
+    let mut divergences = Vec::new();
+    compare_by_id(
+        &mut divergences,
+        ours.artists.iter().map(Option::as_ref),
+        theirs.artists.iter().map(Option::as_ref),
+        |i| EntityRef::Artist(ArtistId(i)),
+        |divergences, entity, ours: &ArtistMetaData, theirs| {
+            let fields = field_names(
+                artist_meta_data_diffs(ours, theirs),
+                ArtistMetaDataDiff::field_name,
+            );
+            let skip = [
+                ("seq_id", ours.seq_id != theirs.seq_id),
+                ("profile_image", ours.profile_image != theirs.profile_image),
+                ("memberships", ours.memberships != theirs.memberships),
+            ];
+            push_fields(divergences, entity, fields, &skip);
+        },
+    );
+    compare_by_id(
+        &mut divergences,
+        ours.releases.iter().map(Some),
+        theirs.releases.iter().map(Some),
+        |i| EntityRef::Release(ReleaseId(i)),
+        compare_releases,
+    );
+    compare_by_id(
+        &mut divergences,
+        ours.events.iter().map(Some),
+        theirs.events.iter().map(Some),
+        |i| EntityRef::Event(EventId(i)),
+        |divergences, entity, ours: &Event, theirs| {
+            let fields = field_names(event_diffs(ours, theirs), EventDiff::field_name);
+            push_fields(
+                divergences,
+                entity,
+                fields,
+                &[("seq_id", ours.seq_id != theirs.seq_id)],
+            );
+        },
+    );
+    compare_by_id(
+        &mut divergences,
+        ours.tags.iter().map(Some),
+        theirs.tags.iter().map(Some),
+        |i| EntityRef::Tag(TagId(i)),
+        |divergences, entity, ours: &String, theirs| {
+            push_fields(divergences, entity, Vec::new(), &[("name", ours != theirs)]);
+        },
+    );
+    // locations are keyed by their id rather than their position
+    let location_ids: BTreeSet<LocationId> = ours
+        .locations
+        .iter()
+        .chain(theirs.locations.iter())
+        .map(|(id, _)| *id)
+        .collect();
+    let name = |locations: &[(LocationId, String)], id| {
+        locations
+            .iter()
+            .find(|(location, _)| *location == id)
+            .map(|(_, name)| name.clone())
+    };
+    for id in location_ids {
+        let entity = EntityRef::Location(id);
+        match (name(&ours.locations, id), name(&theirs.locations, id)) {
+            (Some(ours), Some(theirs)) => {
+                push_fields(
+                    &mut divergences,
+                    entity,
+                    Vec::new(),
+                    &[("name", ours != theirs)],
+                );
+            }
+            (Some(_), None) => divergences.push(Divergence::OnlyInSelf(entity)),
+            (None, Some(_)) => divergences.push(Divergence::OnlyInOther(entity)),
+            (None, None) => {}
+        }
+    }
+    divergences
+}
+
+impl<'a, L: LogStore> States<'a, L> {
+    // empty if both have the same entities, see `snapshot_divergences` for the order
+    pub fn diff_against<M: LogStore>(
+        &self,
+        other: &States<'_, M>,
+    ) -> Result<Vec<Divergence>, InternalErr> {
+        let ours = self.snapshot()?;
+        let theirs = other.snapshot()?;
+        Ok(snapshot_divergences(&ours, &theirs))
+    }
+}
@@ -1,5 +1,8 @@
-use super::defs::{ArtistId, EventId, LocalId, ReleaseId, TagId, TrackRef};
+use super::defs::{ArtistId, DateError, EventId, LocalId, LocationId, ReleaseId, TagId, TrackRef};
+use super::hashes::Hash128;
 use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::fmt;
 use std::sync::PoisonError;
 
 #[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
@@ -8,17 +11,44 @@ pub enum InternalErr {
     InvalidEventId(EventId),
     InvalidLocalId(LocalId),
     InvalidTagId(TagId),
+    // unknown, or already taken when adding a location
+    InvalidLocationId(LocationId),
     InvalidReleaseId(ReleaseId),
     InvalidTrackRef(TrackRef),
-    Poisoned,
-    OutdatedUpdate,
+    // input rejected by `LocalId::parse` or `LocationId::parse`, as text so it is not interned
+    MalformedLocalId(String),
+    MalformedLocationId(String),
+    IndexOutOfBounds(usize),
+    // carries the name of the poisoned lock
+    Poisoned(String),
+    // carries the current seq_id, so the client can rebase and retry right away
+    OutdatedUpdate {
+        current: Hash128,
+    },
     InvalidRelation,
+    // a bulk import failed after `committed` items were imported
+    PartialImport {
+        committed: usize,
+        err: Box<InternalErr>,
+    },
     Other(String),
 }
 
-impl<T> From<PoisonError<T>> for InternalErr {
-    fn from(_: PoisonError<T>) -> InternalErr {
-        InternalErr::Poisoned
+// names the lock in the error when it is poisoned, e.g. `self.artists.read().named("artists")?`
+pub trait LockName<G> {
+    fn named(self, name: &'static str) -> Result<G, InternalErr>;
+}
+
+impl<G> LockName<G> for Result<G, PoisonError<G>> {
+    fn named(self, name: &'static str) -> Result<G, InternalErr> {
+        self.map_err(|_| InternalErr::Poisoned(name.to_owned()))
+    }
+}
+
+// keeps the message of date validation, which used to build `Other` itself
+impl From<DateError> for InternalErr {
+    fn from(err: DateError) -> InternalErr {
+        InternalErr::Other(err.to_string())
     }
 }
 
@@ -27,3 +57,46 @@ impl From<String> for InternalErr {
         InternalErr::Other(s)
     }
 }
+
+impl fmt::Display for InternalErr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InternalErr::InvalidArtistId(id) => write!(f, "invalid artist id: {}", id.0),
+            InternalErr::InvalidEventId(id) => write!(f, "invalid event id: {}", id.0),
+            InternalErr::InvalidLocalId(id) => write!(f, "invalid local id: {}", id.0),
+            InternalErr::InvalidTagId(id) => write!(f, "invalid tag id: {}", id.0),
+            InternalErr::InvalidLocationId(id) => write!(f, "invalid location id: {}", id.0),
+            InternalErr::InvalidReleaseId(id) => write!(f, "invalid release id: {}", id.0),
+            InternalErr::InvalidTrackRef(track) => write!(
+                f,
+                "invalid track: release {} disc {} track {}",
+                track.release_id.0, track.track_num.disc_num, track.track_num.track_num
+            ),
+            InternalErr::MalformedLocalId(tag) => write!(f, "malformed local id: {tag}"),
+            InternalErr::MalformedLocationId(code) => write!(f, "malformed location id: {code}"),
+            InternalErr::IndexOutOfBounds(i) => write!(f, "index out of bounds: {i}"),
+            InternalErr::Poisoned(name) => write!(f, "{name} lock poisoned by a panicked writer"),
+            InternalErr::OutdatedUpdate { current } => {
+                write!(
+                    f,
+                    "update based on an outdated seq_id, current: {:x}",
+                    current.0
+                )
+            }
+            InternalErr::InvalidRelation => write!(f, "invalid relation"),
+            InternalErr::PartialImport { committed, err } => {
+                write!(f, "import failed after {committed} items: {err}")
+            }
+            InternalErr::Other(msg) => write!(f, "{msg}"),
+        }
+    }
+}
+
+impl Error for InternalErr {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            InternalErr::PartialImport { err, .. } => Some(err.as_ref()),
+            _ => None,
+        }
+    }
+}
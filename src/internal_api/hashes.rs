@@ -1,6 +1,9 @@
+use super::defs::UserId;
 use rustc_stable_hash::{FromStableHash, SipHasher128Hash, StableSipHasher128};
+use safe_mix::triplet_mix;
 use serde::{Deserialize, Serialize};
-use std::hash::Hash;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
 
 #[derive(
     Copy, Clone, Debug, PartialOrd, Ord, PartialEq, Eq, Hash, Serialize, Deserialize, Default,
@@ -23,3 +26,90 @@ pub fn get_hash<T: Hash>(v: &T) -> Hash128 {
     v.hash(&mut hasher);
     hasher.finish()
 }
+
+// hash that does not depend on the order of set-like collections (map entries, tags)
+//
+// Logically equal records hash identically regardless of insertion order. Entity seq_ids are
+// history, not content, so they are left out.
+pub trait CanonicalHash {
+    fn canonical_hash<H: Hasher>(&self, state: &mut H);
+
+    // e.g. to check that two entities, such as a replayed one and its original, have the same
+    // content even if their maps were filled in a different order
+    fn content_hash(&self) -> Hash128 {
+        get_canonical_hash(self)
+    }
+}
+
+pub fn get_canonical_hash<T: CanonicalHash + ?Sized>(v: &T) -> Hash128 {
+    let mut hasher = StableSipHasher128::new();
+    v.canonical_hash(&mut hasher);
+    hasher.finish()
+}
+
+impl<T: CanonicalHash> CanonicalHash for Option<T> {
+    fn canonical_hash<H: Hasher>(&self, state: &mut H) {
+        self.is_some().hash(state);
+        if let Some(v) = self {
+            v.canonical_hash(state);
+        }
+    }
+}
+
+impl<T: CanonicalHash> CanonicalHash for [T] {
+    fn canonical_hash<H: Hasher>(&self, state: &mut H) {
+        self.len().hash(state);
+        self.iter().for_each(|v| v.canonical_hash(state));
+    }
+}
+
+// hashes the items in sorted order
+pub fn hash_unordered<T: Ord + Hash, H: Hasher>(items: &[T], state: &mut H) {
+    let mut sorted: Vec<_> = items.iter().collect();
+    sorted.sort();
+    sorted.hash(state);
+}
+
+// hashes the entries in key order, values through `hash_value`
+pub fn hash_map_sorted<K: Ord + Hash, V, H: Hasher>(
+    map: &HashMap<K, V>,
+    state: &mut H,
+    mut hash_value: impl FnMut(&V, &mut H),
+) {
+    let mut entries: Vec<_> = map.iter().collect();
+    entries.sort_by(|a, b| a.0.cmp(b.0));
+    entries.len().hash(state);
+    for (k, v) in entries {
+        k.hash(state);
+        hash_value(v, state);
+    }
+}
+
+// content hash of a file, see `ContentFileId`
+pub fn file_id_for(bytes: &[u8]) -> Hash128 {
+    get_hash(&bytes)
+}
+
+// seq_id after `user` applied an update whose diff hashes to `diff_hash`
+//
+// The user is mixed in, so the same edit made by two users from the same base gives two seq_ids.
+// It goes in as its hash, since the bitwise mix of a small id would clear most bits. This
+// changed the derivation: seq_ids computed before, e.g. in snapshots or held by clients, no
+// longer match the ones replay computes, and updates based on them are `OutdatedUpdate`.
+pub fn next_seq_id(seq_id: Hash128, diff_hash: Hash128, user: UserId) -> Hash128 {
+    Hash128(triplet_mix(&[seq_id.0, diff_hash.0, get_hash(&user.0).0]).unwrap())
+}
+
+// expected seq_id after applying `diffs` in order, each by its user, starting from `initial`
+//
+// This must stay byte-for-byte compatible with the live update path, otherwise replayed
+// entities can no longer be checked against their recorded seq_id. Both go through
+// `next_seq_id` over `get_hash` of the recorded diff (or the whole batch for batch updates).
+// Setters that bump a seq_id without taking one, e.g. of urls, have no diff: they hash the
+// recorded payload, see `Operation::payload_hash`, so their records are checked by passing the
+// `payload` strings of the records as the diffs.
+pub fn verify_seq_chain<T: Hash>(initial: Hash128, diffs: &[(UserId, T)]) -> Hash128 {
+    diffs.iter().fold(initial, |seq_id, (user, diff)| {
+        next_seq_id(seq_id, get_hash(diff), *user)
+    })
+}
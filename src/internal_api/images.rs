@@ -0,0 +1,138 @@
+// Updates of image fields
+//
+// Images are `#[skip_diff]`, so they are not part of metadata updates. Like url updates, these do
+// not take a seq_id but bump it. Setting the image an entity already has is not recorded.
+
+use super::States;
+use super::defs::*;
+use super::errors::{InternalErr, LockName};
+use super::hashes::*;
+use super::operation::Operation;
+use super::wal::LogStore;
+
+impl<'a, L: LogStore> States<'a, L> {
+    // `None` clears the image
+    pub fn set_profile_image(
+        &self,
+        user: UserId,
+        id: ArtistId,
+        image: Option<Image>,
+    ) -> Result<(), InternalErr> {
+        let _notify = self.notify_on_return();
+        let artists = self.artists.read().named("artists")?;
+        let mut artist = artists
+            .get(id.0)
+            .ok_or(InternalErr::InvalidArtistId(id))?
+            .write()
+            .named("artist")?;
+        let artist = artist.as_mut().ok_or(InternalErr::InvalidArtistId(id))?;
+        if artist.profile_image == image {
+            return Ok(());
+        }
+        let op = Operation::ProfileImageSet {
+            id,
+            image: image.clone(),
+        };
+        let hash = op.payload_hash()?;
+        self.record(user, &op)?;
+        artist.seq_id = next_seq_id(artist.seq_id, hash, user);
+        artist.profile_image = image;
+        Ok(())
+    }
+
+    // also adds the image to `images` if it is not there yet, in the same record
+    pub fn set_cover_art(
+        &self,
+        user: UserId,
+        id: ReleaseId,
+        image: Image,
+    ) -> Result<(), InternalErr> {
+        let _notify = self.notify_on_return();
+        self.update_release_images(id, |release| {
+            if release.cover_art.as_ref() == Some(&image) && release.images.contains(&image) {
+                return Ok(());
+            }
+            let op = Operation::CoverArtSet {
+                id,
+                image: image.clone(),
+            };
+            let hash = op.payload_hash()?;
+            self.record(user, &op)?;
+            release.seq_id = next_seq_id(release.seq_id, hash, user);
+            if !release.images.contains(&image) {
+                release.images.push(image.clone());
+            }
+            release.cover_art = Some(image);
+            Ok(())
+        })
+    }
+
+    // adds an image to `images` only, an image already there is not added again
+    pub fn add_release_image(
+        &self,
+        user: UserId,
+        id: ReleaseId,
+        image: Image,
+    ) -> Result<(), InternalErr> {
+        let _notify = self.notify_on_return();
+        self.update_release_images(id, |release| {
+            if release.images.contains(&image) {
+                return Ok(());
+            }
+            let op = Operation::ReleaseImageAdd {
+                id,
+                image: image.clone(),
+            };
+            let hash = op.payload_hash()?;
+            self.record(user, &op)?;
+            release.seq_id = next_seq_id(release.seq_id, hash, user);
+            release.images.push(image);
+            Ok(())
+        })
+    }
+
+    fn update_release_images(
+        &self,
+        id: ReleaseId,
+        update: impl FnOnce(&mut Release) -> Result<(), InternalErr>,
+    ) -> Result<(), InternalErr> {
+        let releases = self.releases.read().named("releases")?;
+        let mut release = releases
+            .get(id.0)
+            .ok_or(InternalErr::InvalidReleaseId(id))?
+            .write()
+            .named("release")?;
+        update(&mut release)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::wal::NaiveLogStore;
+    use super::*;
+
+    #[test]
+    fn cover_art_diff_takes_one_of_the_images() {
+        let wal = NaiveLogStore::new();
+        let states = States::new(&wal);
+        let user = UserId(1);
+        let id = states.release_add(user, "release".to_owned()).unwrap();
+        let image = Image {
+            id: FileId(7),
+            descriptions: LocalizedStrings::new(),
+        };
+        let diff = ReleaseDiff::CoverArt(Some(image.clone()));
+        let seq_id = states.get_release(id).unwrap().seq_id;
+        assert!(
+            states
+                .release_metadata_update(user, id, diff.clone(), seq_id, true)
+                .is_err()
+        );
+        states.add_release_image(user, id, image.clone()).unwrap();
+        let seq_id = states.get_release(id).unwrap().seq_id;
+        states
+            .release_metadata_update(user, id, diff, seq_id, true)
+            .unwrap();
+        assert_eq!(states.get_release(id).unwrap().cover_art, Some(image));
+    }
+}
@@ -0,0 +1,111 @@
+// Bulk import of artists, e.g. from spreadsheet rows
+
+use super::States;
+use super::defs::*;
+use super::errors::InternalErr;
+use super::hashes::Hash128;
+use super::wal::LogStore;
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ArtistImportRow {
+    pub name: String,
+    pub birthyear: Option<u16>,
+    pub kind: Option<ArtistKind>,
+    #[serde(default)]
+    pub aliases: Vec<StringWithLocal>,
+}
+
+impl<'a, L: LogStore> States<'a, L> {
+    // Every row is a single "artist_add" of the whole artist, so a row is either imported or
+    // not recorded at all. Import stops at the first failing row and returns
+    // `InternalErr::PartialImport` with the number of rows imported before it, those artists
+    // are kept.
+    pub fn import_artists(
+        &self,
+        user: UserId,
+        rows: impl Iterator<Item = ArtistImportRow>,
+    ) -> Result<Vec<ArtistId>, InternalErr> {
+        self.reserve_artists(rows.size_hint().0)?;
+        let mut ids = Vec::new();
+        for row in rows {
+            let artist = ArtistMetaData {
+                name: row.name,
+                aliases: row.aliases,
+                kind: row.kind,
+                birthyear: row.birthyear,
+                seq_id: Hash128(0),
+                ..Default::default()
+            };
+            let id =
+                self.artist_add_full(user, artist)
+                    .map_err(|err| InternalErr::PartialImport {
+                        committed: ids.len(),
+                        err: Box::new(err),
+                    })?;
+            ids.push(id);
+        }
+        Ok(ids)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::wal::NaiveLogStore;
+    use super::*;
+    use serde::Serialize;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    // fails every record after the first `capacity`
+    struct FullLogStore {
+        inner: NaiveLogStore,
+        capacity: AtomicUsize,
+    }
+
+    impl LogStore for FullLogStore {
+        fn record<T: Serialize>(
+            &self,
+            user: UserId,
+            api_name: &str,
+            payload: &T,
+        ) -> Result<u64, String> {
+            self.capacity
+                .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| n.checked_sub(1))
+                .map_err(|_| "log is full".to_owned())?;
+            self.inner.record(user, api_name, payload)
+        }
+    }
+
+    fn row(name: &str) -> ArtistImportRow {
+        ArtistImportRow {
+            name: name.to_owned(),
+            birthyear: Some(2000),
+            kind: Some(ArtistKind::Solo),
+            aliases: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn failed_rows_leave_nothing_behind() {
+        let wal = FullLogStore {
+            inner: NaiveLogStore::new(),
+            capacity: AtomicUsize::new(2),
+        };
+        let states = States::new(&wal);
+        let rows = ["a", "b", "c"].map(row);
+        let err = states
+            .import_artists(UserId(1), rows.into_iter())
+            .unwrap_err();
+        let InternalErr::PartialImport { committed, .. } = err else {
+            panic!("{err}");
+        };
+        assert_eq!(committed, 2);
+        // every artist in the states and the log is one of the committed rows
+        assert_eq!(states.next_artist_id(), ArtistId(2));
+        assert_eq!(wal.inner.read_all().unwrap().len(), 2);
+        let artist = states.get_artist(ArtistId(1)).unwrap();
+        assert_eq!(artist.name, "b");
+        assert_eq!(artist.birthyear, Some(2000));
+        assert_eq!(artist.kind, Some(ArtistKind::Solo));
+    }
+}
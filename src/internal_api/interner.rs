@@ -0,0 +1,36 @@
+// Statistics of the string interner
+//
+// `LocalId`, `LocationId` and the `Other` roles and relation kinds are `Ustr`s. `ustr` keeps one
+// cache for the whole process and never frees an interned string, not even when no `Ustr` refers
+// to it anymore, so the cache only grows. Free text such as roles adds to it, while rejected
+// locale or location codes are kept out of it. There is no way to shrink it short of restarting
+// the process, so `ustr_stats` is for monitoring its growth.
+
+use super::States;
+use super::wal::LogStore;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct UstrStats {
+    // distinct interned strings
+    pub entries: usize,
+    // total length of the interned strings
+    pub string_bytes: usize,
+    // memory used by the cache, including its per-string overhead
+    pub allocated_bytes: usize,
+    // memory reserved by the cache
+    pub capacity_bytes: usize,
+}
+
+impl<'a, L: LogStore> States<'a, L> {
+    // of the whole process rather than of these states, strings interned by other states or by
+    // other users of `ustr` are counted too
+    // strings interned while the stats are collected may or may not be counted
+    pub fn ustr_stats(&self) -> UstrStats {
+        UstrStats {
+            entries: ustr::num_entries(),
+            string_bytes: ustr::string_cache_iter().map(str::len).sum(),
+            allocated_bytes: ustr::total_allocated(),
+            capacity_bytes: ustr::total_capacity(),
+        }
+    }
+}
@@ -0,0 +1,78 @@
+// Lyric documents of tracks
+//
+// `Song::lyrics` is `#[skip_diff]`, it has one document per language. Like url updates, these do
+// not take a seq_id but bump the seq_id of the song. Attaching the document a language already
+// has, or detaching a language without one, is not recorded.
+
+use super::States;
+use super::defs::*;
+use super::errors::{InternalErr, LockName};
+use super::hashes::*;
+use super::operation::Operation;
+use super::wal::LogStore;
+
+impl<'a, L: LogStore> States<'a, L> {
+    // replaces the document of `local` if there is one
+    // `local` has to be in the normalized form of `LocalId::parse`
+    pub fn attach_lyrics(
+        &self,
+        user: UserId,
+        track: TrackRef,
+        local: LocalId,
+        file: FileId,
+    ) -> Result<(), InternalErr> {
+        let _notify = self.notify_on_return();
+        if LocalId::parse(&local.0)? != local {
+            return Err(InternalErr::InvalidLocalId(local));
+        }
+        self.update_lyrics(track, |song| {
+            if song.lyrics.get(&local) == Some(&file) {
+                return Ok(());
+            }
+            let op = Operation::LyricsAttach { track, local, file };
+            let hash = op.payload_hash()?;
+            self.record(user, &op)?;
+            song.seq_id = next_seq_id(song.seq_id, hash, user);
+            song.lyrics.insert(local, file);
+            Ok(())
+        })
+    }
+
+    pub fn detach_lyrics(
+        &self,
+        user: UserId,
+        track: TrackRef,
+        local: LocalId,
+    ) -> Result<(), InternalErr> {
+        let _notify = self.notify_on_return();
+        self.update_lyrics(track, |song| {
+            if !song.lyrics.contains_key(&local) {
+                return Ok(());
+            }
+            let op = Operation::LyricsDetach { track, local };
+            let hash = op.payload_hash()?;
+            self.record(user, &op)?;
+            song.seq_id = next_seq_id(song.seq_id, hash, user);
+            song.lyrics.remove(&local);
+            Ok(())
+        })
+    }
+
+    fn update_lyrics(
+        &self,
+        track: TrackRef,
+        update: impl FnOnce(&mut Song) -> Result<(), InternalErr>,
+    ) -> Result<(), InternalErr> {
+        let releases = self.releases.read().named("releases")?;
+        let mut release = releases
+            .get(track.release_id.0)
+            .ok_or(InternalErr::InvalidReleaseId(track.release_id))?
+            .write()
+            .named("release")?;
+        let song = release
+            .tracks
+            .get_mut(&track.track_num)
+            .ok_or(InternalErr::InvalidTrackRef(track))?;
+        update(song)
+    }
+}
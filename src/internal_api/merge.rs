@@ -0,0 +1,86 @@
+// Three-way merge of concurrent artist edits
+//
+// Two clients start from the same seq_id; `a` is what the first one applied, `b` what the second
+// one tried to apply and got `OutdatedUpdate` for. Diffs are matched by the field they touch,
+// so edits of different fields merge, and any edit of a field `a` also touched is a conflict,
+// even if both set the same value.
+
+use super::defs::*;
+use std::collections::HashSet;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Conflict {
+    pub field: &'static str,
+    // the diffs of `a` and of `b` on the field, in their original order
+    pub applied: Vec<ArtistMetaDataDiff>,
+    pub pending: Vec<ArtistMetaDataDiff>,
+}
+
+// the diffs of `b` that can be applied on top of `a` as they are, in order, and one conflict
+// per field both touched, in the order the fields first appear in `b`
+pub fn merge_diffs(
+    a: &[ArtistMetaDataDiff],
+    b: &[ArtistMetaDataDiff],
+) -> (Vec<ArtistMetaDataDiff>, Vec<Conflict>) {
+    let touched: HashSet<_> = a.iter().map(|diff| diff.field_name()).collect();
+    let mut merged = Vec::new();
+    let mut conflicts: Vec<Conflict> = Vec::new();
+    for diff in b.iter() {
+        let field = diff.field_name();
+        if !touched.contains(field) {
+            merged.push(diff.clone());
+        } else if let Some(conflict) = conflicts.iter_mut().find(|c| c.field == field) {
+            conflict.pending.push(diff.clone());
+        } else {
+            conflicts.push(Conflict {
+                field,
+                applied: a
+                    .iter()
+                    .filter(|diff| diff.field_name() == field)
+                    .cloned()
+                    .collect(),
+                pending: vec![diff.clone()],
+            });
+        }
+    }
+    (merged, conflicts)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn diffs_on_fields_both_touched_conflict() {
+        let name = |name: &str| ArtistMetaDataDiff::Name(name.to_owned());
+        let alias = |i| ArtistMetaDataDiff::Aliases(VecOp::RemoveAt(i));
+        let birthyear = ArtistMetaDataDiff::Birthyear(Some(2000));
+        let a = [name("a"), alias(0), name("a again")];
+        let b = [birthyear.clone(), alias(1), name("b"), alias(2)];
+        let (merged, conflicts) = merge_diffs(&a, &b);
+        assert_eq!(merged, vec![birthyear]);
+        assert_eq!(
+            conflicts,
+            vec![
+                Conflict {
+                    field: "aliases",
+                    applied: vec![alias(0)],
+                    pending: vec![alias(1), alias(2)],
+                },
+                Conflict {
+                    field: "name",
+                    applied: vec![name("a"), name("a again")],
+                    pending: vec![name("b")],
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn disjoint_edits_merge_cleanly() {
+        let a = [ArtistMetaDataDiff::Name("a".to_owned())];
+        let b = [ArtistMetaDataDiff::Birthyear(None)];
+        assert_eq!(merge_diffs(&a, &b), (b.to_vec(), vec![]));
+        assert_eq!(merge_diffs(&[], &a), (a.to_vec(), vec![]));
+    }
+}
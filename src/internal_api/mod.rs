@@ -1,6 +1,35 @@
+pub mod append_vec;
+#[cfg(feature = "async")]
+pub mod async_wal;
+pub mod builder;
+mod compact;
+#[cfg(feature = "compress")]
+pub mod compress;
 pub mod defs;
+mod derived;
+pub mod divergence;
 pub mod errors;
 pub mod hashes;
+mod images;
+pub mod import;
+pub mod interner;
+mod lyrics;
+pub mod merge;
+pub mod operation;
+pub mod policy;
+pub mod query;
+mod recover;
+mod relations;
+mod replace;
+mod replay;
+mod slot;
+pub mod snapshot;
+pub mod subscribe;
+#[cfg(test)]
+mod test_util;
+pub mod txn;
+mod urls;
+mod validate;
 pub mod wal;
 
 // Internal API structs
@@ -12,63 +41,490 @@ pub mod wal;
 // resolve for the user. We try to factor out those that do not require resolving, so we can use
 // them directly in the user-facing APIs.
 
-use defs::*;
-use errors::InternalErr;
+// `defs` holds the only definitions of the entity and id types, re-exported here
+use append_vec::AppendVec;
+pub use defs::*;
+use errors::{InternalErr, LockName};
 use hashes::*;
-use safe_mix::triplet_mix;
-use std::collections::HashMap;
-use std::sync::RwLock;
+use operation::Operation;
+use policy::{AllowAll, EditPolicy};
+use slot::Slot;
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::SyncSender;
+use std::sync::{Mutex, RwLock};
 use std::vec::Vec;
+use subscribe::{ChangeEvent, PendingChanges};
 use wal::LogStore;
 
+// `States` borrows its WAL rather than owning it: the WAL has to outlive the states, and the
+// same WAL can still be read, e.g. for `compact`, while the states are in use. `with_wal` moves
+// the states over to another WAL, see `replay`.
 pub struct States<'a, L: LogStore> {
     wal: &'a L,
+    policy: &'a dyn EditPolicy,
 
-    artists: RwLock<Vec<RwLock<ArtistMetaData>>>,
-    releases: RwLock<Vec<RwLock<Release>>>,
-    events: RwLock<Vec<RwLock<Event>>>,
+    // Entities never move once added, so adding one only takes the outer lock for reading and
+    // does not block readers of other entities. The outer write lock is for operations that
+    // need all entities of a kind to stay fixed, e.g. merges and snapshots.
+    // removed artists are left as `None` so the ids of later artists stay stable, see `Slot` for
+    // the lock of each artist
+    artists: RwLock<AppendVec<Slot<Option<ArtistMetaData>>>>,
+    releases: RwLock<AppendVec<RwLock<Release>>>,
+    events: RwLock<AppendVec<RwLock<Event>>>,
+    // the id of the next artist, see `allocate_artist_id`
+    next_artist_id: AtomicUsize,
+    // tag names, a `TagId` is an index into it
+    tags: RwLock<Vec<String>>,
+    // location names by id, in the order they were added
+    locations: RwLock<Vec<(LocationId, String)>>,
 
     // derived
     group_members: RwLock<HashMap<ArtistId, Vec<ArtistId>>>,
     artist_discography: RwLock<HashMap<ArtistId, Vec<TrackRef>>>,
     derived_songs: RwLock<HashMap<TrackRef, Vec<(TrackRef, SongRelationKind)>>>,
+
+    // sequence number of the latest WAL record applied to these states
+    last_seq: Mutex<Option<u64>>,
+
+    // see `subscribe`
+    subscribers: Mutex<Vec<SyncSender<ChangeEvent>>>,
+    pending_changes: Mutex<PendingChanges>,
 }
 
 impl<'a, L: LogStore> States<'a, L> {
+    // empty states, recording every change into `wal`
+    pub fn new(wal: &'a L) -> Self {
+        States {
+            wal,
+            policy: &AllowAll,
+            artists: RwLock::new(AppendVec::new()),
+            releases: RwLock::new(AppendVec::new()),
+            events: RwLock::new(AppendVec::new()),
+            next_artist_id: AtomicUsize::new(0),
+            tags: RwLock::new(Vec::new()),
+            locations: RwLock::new(Vec::new()),
+            group_members: RwLock::new(HashMap::new()),
+            artist_discography: RwLock::new(HashMap::new()),
+            derived_songs: RwLock::new(HashMap::new()),
+            last_seq: Mutex::new(None),
+            subscribers: Mutex::new(Vec::new()),
+            pending_changes: Mutex::new(PendingChanges::default()),
+        }
+    }
+
+    // empty states with room for the given number of entities, e.g. before a bulk import
+    pub fn with_capacity(wal: &'a L, artists: usize, releases: usize, events: usize) -> Self {
+        States {
+            artists: RwLock::new(AppendVec::with_capacity(artists)),
+            releases: RwLock::new(AppendVec::with_capacity(releases)),
+            events: RwLock::new(AppendVec::with_capacity(events)),
+            ..States::new(wal)
+        }
+    }
+
+    // makes room for `n` more artists, so adding them does not allocate
+    pub fn reserve_artists(&self, n: usize) -> Result<(), InternalErr> {
+        self.artists.read().named("artists")?.reserve(n);
+        Ok(())
+    }
+
+    // moves the states over to another WAL, the edit policy is reset to `AllowAll`
+    fn with_wal<'b, M: LogStore>(self, wal: &'b M) -> States<'b, M> {
+        States {
+            wal,
+            policy: &AllowAll,
+            artists: self.artists,
+            releases: self.releases,
+            events: self.events,
+            next_artist_id: self.next_artist_id,
+            tags: self.tags,
+            locations: self.locations,
+            group_members: self.group_members,
+            artist_discography: self.artist_discography,
+            derived_songs: self.derived_songs,
+            last_seq: self.last_seq,
+            subscribers: self.subscribers,
+            pending_changes: self.pending_changes,
+        }
+    }
+
+    // checks the edit policy, records into the WAL and remembers the sequence number for snapshots
+    fn record(&self, user: UserId, op: &Operation) -> Result<(), InternalErr> {
+        self.record_with(user, op, Vec::new())
+    }
+
+    // `added` is the entity the operation adds, for the change event
+    fn record_with(
+        &self,
+        user: UserId,
+        op: &Operation,
+        added: Vec<EntityRef>,
+    ) -> Result<(), InternalErr> {
+        self.record_event(user, op, added, op.fields())
+    }
+
+    // `fields` are the fields the operation changes, for the change event, see `Operation::fields`
+    fn record_event(
+        &self,
+        user: UserId,
+        op: &Operation,
+        added: Vec<EntityRef>,
+        fields: Vec<&'static str>,
+    ) -> Result<(), InternalErr> {
+        self.policy.check(user, op.api_name())?;
+        // held while the WAL assigns the seq, so changes are queued in seq order
+        let mut last_seq = self.last_seq.lock().named("last_seq")?;
+        let seq = self.wal.record_op(user, op)?;
+        *last_seq = (*last_seq).max(Some(seq));
+        self.queue_change(seq, user, op, added, fields);
+        Ok(())
+    }
+
     pub fn artist_add(&self, user: UserId, name: String) -> Result<ArtistId, InternalErr> {
         let artist = ArtistMetaData {
             name,
             seq_id: Hash128(0),
             ..Default::default()
         };
-        let mut artists = self.artists.write()?;
-        self.wal.record(user, "artist_add", &artist)?;
-        artists.push(RwLock::new(artist));
-        Ok(ArtistId(artists.len() - 1))
+        self.artist_add_full(user, artist)
+    }
+
+    // `artist` must have no memberships, they are not indexed
+    fn artist_add_full(
+        &self,
+        user: UserId,
+        artist: ArtistMetaData,
+    ) -> Result<ArtistId, InternalErr> {
+        let _notify = self.notify_on_return();
+        // recorded under the push lock, so the WAL has the adds in id order
+        let mut id = ArtistId(0);
+        self.artists.read().named("artists")?.push_with(|i| {
+            id = self.allocate_artist_id(i);
+            let recorded = self.record_with(
+                user,
+                &Operation::ArtistAdd(artist.clone()),
+                vec![EntityRef::Artist(id)],
+            );
+            if let Err(err) = recorded {
+                // nothing is pushed, the id goes to the next add
+                self.next_artist_id.fetch_sub(1, Ordering::Relaxed);
+                return Err(err);
+            }
+            Ok(Slot::new(Some(artist)))
+        })?;
+        Ok(id)
+    }
+
+    // The id of an artist comes from a counter rather than from its position in `artists`. It
+    // is only called under the push lock of `artists`, so ids are handed out in push order and,
+    // while every artist keeps its slot, match the slot `i` the artist is pushed to.
+    fn allocate_artist_id(&self, i: usize) -> ArtistId {
+        let id = self.next_artist_id.fetch_add(1, Ordering::Relaxed);
+        debug_assert_eq!(id, i, "artist id out of step with its slot");
+        ArtistId(id)
+    }
+
+    // the id the next `artist_add` will get, unless another add comes first
+    pub fn next_artist_id(&self) -> ArtistId {
+        ArtistId(self.next_artist_id.load(Ordering::Relaxed))
     }
 
     pub fn release_add(&self, user: UserId, title: String) -> Result<ReleaseId, InternalErr> {
+        let _notify = self.notify_on_return();
         let release = Release {
             title,
             seq_id: Hash128(0),
             ..Default::default()
         };
-        let mut releases = self.releases.write()?;
-        self.wal.record(user, "release_add", &release)?;
-        releases.push(RwLock::new(release));
-        Ok(ReleaseId(releases.len() - 1))
+        let id = self.releases.read().named("releases")?.push_with(|i| {
+            self.record_with(
+                user,
+                &Operation::ReleaseAdd(release.clone()),
+                vec![EntityRef::Release(ReleaseId(i))],
+            )?;
+            Ok::<_, InternalErr>(RwLock::new(release))
+        })?;
+        Ok(ReleaseId(id))
+    }
+
+    // Adds a release with all its fields and tracks as one record. The ids it refers to are
+    // checked like `validate` does, and originals may also be tracks of the release itself, as
+    // long as they do not form a cycle. Credits are normalized, and the seq_ids of the release
+    // and its tracks start from their content hash.
+    pub fn release_add_full(
+        &self,
+        user: UserId,
+        mut release: Release,
+    ) -> Result<ReleaseId, InternalErr> {
+        let _notify = self.notify_on_return();
+        release.seq_id = release.content_hash();
+        for song in release.tracks.values_mut() {
+            song.normalize_credits();
+            song.seq_id = song.content_hash();
+        }
+        let edges: Vec<_> = release
+            .tracks
+            .iter()
+            .flat_map(|(track_num, song)| {
+                song.originals
+                    .iter()
+                    .map(|(original, kind)| (*track_num, *original, *kind))
+            })
+            .collect();
+        let artists: Vec<_> = release
+            .tracks
+            .iter()
+            .map(|(track_num, song)| {
+                let artists = derived::track_artists(&song.artists, &release.album_artists);
+                (*track_num, artists)
+            })
+            .collect();
+        let mut existing = self.existing()?;
+        let id = self.releases.read().named("releases")?.push_with(|i| {
+            let id = ReleaseId(i);
+            existing
+                .tracks
+                .extend(release.tracks.keys().map(|track_num| TrackRef {
+                    release_id: id,
+                    track_num: *track_num,
+                }));
+            let mut errors = Vec::new();
+            existing.check_release(&release, &mut errors);
+            if let Some(err) = errors.into_iter().next() {
+                return Err(err);
+            }
+            if has_own_cycle(id, &release) {
+                return Err(InternalErr::InvalidRelation);
+            }
+            self.record_with(
+                user,
+                &Operation::ReleaseAdd(release.clone()),
+                vec![EntityRef::Release(id)],
+            )?;
+            Ok(RwLock::new(release))
+        })?;
+
+        for (track_num, artists) in artists {
+            let track = TrackRef {
+                release_id: ReleaseId(id),
+                track_num,
+            };
+            self.index_track_artists(track, &HashSet::new(), &artists)?;
+        }
+        let mut derived_songs = self.derived_songs.write().named("derived_songs")?;
+        for (track_num, original, kind) in edges {
+            let derived = TrackRef {
+                release_id: ReleaseId(id),
+                track_num,
+            };
+            derived::index(&mut derived_songs, original, (derived, kind));
+        }
+        Ok(ReleaseId(id))
     }
 
     pub fn event_add(&self, user: UserId, name: String) -> Result<EventId, InternalErr> {
+        let _notify = self.notify_on_return();
         let event = Event {
             name,
             seq_id: Hash128(0),
             ..Default::default()
         };
-        let mut events = self.events.write()?;
-        self.wal.record(user, "event_add", &event)?;
-        events.push(RwLock::new(event));
-        Ok(EventId(events.len() - 1))
+        let id = self.events.read().named("events")?.push_with(|i| {
+            self.record_with(
+                user,
+                &Operation::EventAdd(event.clone()),
+                vec![EntityRef::Event(EventId(i))],
+            )?;
+            Ok::<_, InternalErr>(RwLock::new(event))
+        })?;
+        Ok(EventId(id))
+    }
+
+    pub fn tag_add(&self, user: UserId, name: String) -> Result<TagId, InternalErr> {
+        let _notify = self.notify_on_return();
+        let mut tags = self.tags.write().named("tags")?;
+        self.record_with(
+            user,
+            &Operation::TagAdd(name.clone()),
+            vec![EntityRef::Tag(TagId(tags.len()))],
+        )?;
+        tags.push(name);
+        Ok(TagId(tags.len() - 1))
+    }
+
+    // the tags set by an update have to exist; tags are never removed, so this can be checked
+    // before locking the entity
+    fn check_tags(&self, tags: &[TagId]) -> Result<(), InternalErr> {
+        let count = self.tags.read().named("tags")?.len();
+        match tags.iter().find(|tag| tag.0 >= count) {
+            Some(tag) => Err(InternalErr::InvalidTagId(*tag)),
+            None => Ok(()),
+        }
+    }
+
+    pub fn tag_name(&self, id: TagId) -> Result<String, InternalErr> {
+        self.tags
+            .read()
+            .named("tags")?
+            .get(id.0)
+            .cloned()
+            .ok_or(InternalErr::InvalidTagId(id))
+    }
+
+    // location ids are chosen by the caller, an id can only be added once
+    pub fn location_add(
+        &self,
+        user: UserId,
+        id: LocationId,
+        name: String,
+    ) -> Result<(), InternalErr> {
+        let _notify = self.notify_on_return();
+        let mut locations = self.locations.write().named("locations")?;
+        if locations.iter().any(|(location, _)| *location == id) {
+            return Err(InternalErr::InvalidLocationId(id));
+        }
+        self.record(
+            user,
+            &Operation::LocationAdd {
+                id,
+                name: name.clone(),
+            },
+        )?;
+        locations.push((id, name));
+        Ok(())
+    }
+
+    pub fn location_name(&self, id: LocationId) -> Result<String, InternalErr> {
+        self.locations
+            .read()
+            .named("locations")?
+            .iter()
+            .find(|(location, _)| *location == id)
+            .map(|(_, name)| name.clone())
+            .ok_or(InternalErr::InvalidLocationId(id))
+    }
+
+    // the slot is kept as a tombstone, replaying the "artist_remove" record tombstones it again
+    // memberships and credits pointing at the removed artist are left as is, but it is no longer
+    // listed as a member of its groups
+    pub fn artist_remove(&self, user: UserId, id: ArtistId) -> Result<(), InternalErr> {
+        let _notify = self.notify_on_return();
+        let artists = self.artists.read().named("artists")?;
+        let mut group_members = self.group_members.write().named("group_members")?;
+        let mut artist = artists
+            .get(id.0)
+            .ok_or(InternalErr::InvalidArtistId(id))?
+            .write()
+            .named("artist")?;
+        if artist.is_none() {
+            return Err(InternalErr::InvalidArtistId(id));
+        }
+        self.record(user, &Operation::ArtistRemove(id))?;
+        if let Some(removed) = artist.take() {
+            derived::index_member(&mut group_members, id, &removed.memberships, &[]);
+        }
+        Ok(())
+    }
+
+    // repoints every reference to `from` at `into` and tombstones `from`
+    // `from` loses its memberships to `into`, and the derived indexes are recomputed
+    pub fn artist_merge(
+        &self,
+        user: UserId,
+        from: ArtistId,
+        into: ArtistId,
+    ) -> Result<(), InternalErr> {
+        let _notify = self.notify_on_return();
+        if from == into {
+            return Err(InternalErr::InvalidRelation);
+        }
+        {
+            // the outer write locks keep every other reader and writer out during the merge
+            // artists are still written through their own locks, see `Slot`
+            let artists = self.artists.write().named("artists")?;
+            for id in [from, into] {
+                let artist = artists.get(id.0).ok_or(InternalErr::InvalidArtistId(id))?;
+                if artist.read().named("artist")?.is_none() {
+                    return Err(InternalErr::InvalidArtistId(id));
+                }
+            }
+            let mut releases = self.releases.write().named("releases")?;
+            self.record(user, &Operation::ArtistMerge { from, into })?;
+
+            let from_meta = artists[from.0].write().named("artist")?.take().unwrap();
+            {
+                let mut into_meta = artists[into.0].write().named("artist")?;
+                let into_meta = into_meta.as_mut().unwrap();
+                for membership in from_meta.memberships {
+                    if !into_meta.memberships.contains(&membership) {
+                        into_meta.memberships.push(membership);
+                    }
+                }
+                into_meta
+                    .memberships
+                    .retain(|m| m.group_id != from && m.group_id != into);
+            }
+            for artist in artists.iter() {
+                let mut artist = artist.write().named("artist")?;
+                if let Some(artist) = artist.as_mut() {
+                    repoint(&mut artist.memberships, |m| &mut m.group_id, from, into);
+                }
+            }
+
+            for release in releases.iter_mut() {
+                let release = release.get_mut().named("release")?;
+                repoint(&mut release.album_artists, |a| a, from, into);
+                repoint(&mut release.credits, |(a, _)| a, from, into);
+                for song in release.tracks.values_mut() {
+                    repoint(&mut song.artists, |a| a, from, into);
+                    repoint(&mut song.credits, |(a, _)| a, from, into);
+                }
+            }
+        }
+        self.recompute_group_members()?;
+        self.recompute_discography()?;
+        self.recompute_derived_songs()?;
+        Ok(())
+    }
+
+    pub fn get_artist(&self, id: ArtistId) -> Result<ArtistMetaData, InternalErr> {
+        let artists = self.artists.read().named("artists")?;
+        let artist = artists.get(id.0).ok_or(InternalErr::InvalidArtistId(id))?;
+        artist
+            .read()
+            .named("artist")?
+            .clone()
+            .ok_or(InternalErr::InvalidArtistId(id))
+    }
+
+    pub fn get_release(&self, id: ReleaseId) -> Result<Release, InternalErr> {
+        let releases = self.releases.read().named("releases")?;
+        let release = releases
+            .get(id.0)
+            .ok_or(InternalErr::InvalidReleaseId(id))?;
+        Ok(release.read().named("release")?.clone())
+    }
+
+    // the song of `track`, copied out so no lock is held afterwards
+    pub fn resolve_track(&self, track: TrackRef) -> Result<Song, InternalErr> {
+        let releases = self.releases.read().named("releases")?;
+        let release = releases
+            .get(track.release_id.0)
+            .ok_or(InternalErr::InvalidReleaseId(track.release_id))?
+            .read()
+            .named("release")?;
+        release
+            .tracks
+            .get(&track.track_num)
+            .cloned()
+            .ok_or(InternalErr::InvalidTrackRef(track))
+    }
+
+    pub fn get_event(&self, id: EventId) -> Result<Event, InternalErr> {
+        let events = self.events.read().named("events")?;
+        let event = events.get(id.0).ok_or(InternalErr::InvalidEventId(id))?;
+        Ok(event.read().named("event")?.clone())
     }
 
     pub fn artist_metadata_update(
@@ -76,25 +532,517 @@ impl<'a, L: LogStore> States<'a, L> {
         user: UserId,
         id: ArtistId,
         diff: ArtistMetaDataDiff,
-        mut seq_id: Hash128,
+        seq_id: Hash128,
         update_seq_id: bool,
     ) -> Result<Hash128, InternalErr> {
-        let hash = get_hash(&diff);
-        let artists = self.artists.read()?;
+        self.artist_metadata_update_with_inverse(user, id, diff, seq_id, update_seq_id)
+            .map(|(seq_id, _)| seq_id)
+    }
+
+    // same as artist_metadata_update, but also returns the diff that undoes this update
+    pub fn artist_metadata_update_with_inverse(
+        &self,
+        user: UserId,
+        id: ArtistId,
+        diff: ArtistMetaDataDiff,
+        seq_id: Hash128,
+        update_seq_id: bool,
+    ) -> Result<(Hash128, ArtistMetaDataDiff), InternalErr> {
+        let _notify = self.notify_on_return();
+        if let ArtistMetaDataDiff::Tags(tags) = &diff {
+            self.check_tags(tags)?;
+        }
+        let artists = self.artists.read().named("artists")?;
         if id.0 >= artists.len() {
             return Err(InternalErr::InvalidArtistId(id));
         }
-        let mut artist = artists[id.0].write()?;
-        // enforce sequential update for each artist metadata
+        // held until the kind is applied, see `check_kind_change`
+        let group_members = match diff {
+            ArtistMetaDataDiff::Kind(_) => Some(self.group_members.read().named("group_members")?),
+            _ => None,
+        };
+        let mut artist = artists[id.0].write().named("artist")?;
+        let artist = artist.as_mut().ok_or(InternalErr::InvalidArtistId(id))?;
+        if let Some(group_members) = &group_members
+            && let ArtistMetaDataDiff::Kind(kind) = diff
+        {
+            relations::check_kind_change(group_members, id, artist.kind, kind)?;
+        }
+        self.versioned_update(user, artist, diff, seq_id, update_seq_id, |diff| {
+            Operation::ArtistMetadataUpdate {
+                id,
+                diff,
+                update_seq_id,
+            }
+        })
+    }
+
+    // applies all diffs in order as a single update: one WAL record with payload
+    // `(id, diffs, update_seq_id)` and one seq_id bump over the hash of the whole batch
+    // if any diff cannot be applied, the artist is left untouched
+    pub fn artist_metadata_update_batch(
+        &self,
+        user: UserId,
+        id: ArtistId,
+        diffs: ArtistMetaDataDiffSet,
+        mut seq_id: Hash128,
+        update_seq_id: bool,
+    ) -> Result<Hash128, InternalErr> {
+        let _notify = self.notify_on_return();
+        for diff in diffs.0.iter() {
+            if let ArtistMetaDataDiff::Tags(tags) = diff {
+                self.check_tags(tags)?;
+            }
+        }
+        let hash = get_hash(&diffs);
+        let artists = self.artists.read().named("artists")?;
+        // held until the kind is applied, see `check_kind_change`
+        let group_members = if diffs
+            .0
+            .iter()
+            .any(|diff| matches!(diff, ArtistMetaDataDiff::Kind(_)))
+        {
+            Some(self.group_members.read().named("group_members")?)
+        } else {
+            None
+        };
+        let mut artist = artists
+            .get(id.0)
+            .ok_or(InternalErr::InvalidArtistId(id))?
+            .write()
+            .named("artist")?;
+        let artist = artist.as_mut().ok_or(InternalErr::InvalidArtistId(id))?;
+        let mut updated = artist.clone();
+        apply_artist_meta_data_diff_set(&mut updated, diffs.clone())?;
+        // a batch that changes nothing is not recorded, like in `versioned_update`
+        if updated == *artist {
+            return Ok(artist.seq_id);
+        }
         if artist.seq_id != seq_id {
-            return Err(InternalErr::OutdatedUpdate);
+            return Err(InternalErr::OutdatedUpdate {
+                current: artist.seq_id,
+            });
+        }
+        if let Some(group_members) = &group_members {
+            relations::check_kind_change(group_members, id, artist.kind, updated.kind)?;
         }
         if update_seq_id {
-            seq_id = Hash128(triplet_mix(&[seq_id.0, hash.0]).unwrap());
-            artist.seq_id = seq_id;
+            seq_id = next_seq_id(seq_id, hash, user);
+            updated.seq_id = seq_id;
+        }
+        self.record(
+            user,
+            &Operation::ArtistMetadataUpdateBatch {
+                id,
+                diffs,
+                update_seq_id,
+            },
+        )?;
+        *artist = updated;
+        Ok(seq_id)
+    }
+
+    pub fn release_metadata_update(
+        &self,
+        user: UserId,
+        id: ReleaseId,
+        diff: ReleaseDiff,
+        seq_id: Hash128,
+        update_seq_id: bool,
+    ) -> Result<Hash128, InternalErr> {
+        let _notify = self.notify_on_return();
+        if let ReleaseDiff::Tags(tags) = &diff {
+            self.check_tags(tags)?;
+        }
+        let releases = self.releases.read().named("releases")?;
+        if id.0 >= releases.len() {
+            return Err(InternalErr::InvalidReleaseId(id));
+        }
+        let mut release = releases[id.0].write().named("release")?;
+        if let ReleaseDiff::CoverArt(Some(image)) = &diff
+            && !release.images.contains(image)
+        {
+            return Err(InternalErr::Other(
+                "cover art is not one of the images of the release".to_owned(),
+            ));
+        }
+        let album_artists = release.album_artists.clone();
+        let (seq_id, _) =
+            self.versioned_update(user, &mut *release, diff, seq_id, update_seq_id, |diff| {
+                Operation::ReleaseMetadataUpdate {
+                    id,
+                    diff,
+                    update_seq_id,
+                }
+            })?;
+        if release.album_artists != album_artists {
+            for (track_num, song) in release.tracks.iter() {
+                let track = TrackRef {
+                    release_id: id,
+                    track_num: *track_num,
+                };
+                self.index_track_artists(
+                    track,
+                    &derived::track_artists(&song.artists, &album_artists),
+                    &derived::track_artists(&song.artists, &release.album_artists),
+                )?;
+            }
+        }
+        Ok(seq_id)
+    }
+
+    pub fn event_metadata_update(
+        &self,
+        user: UserId,
+        id: EventId,
+        diff: EventDiff,
+        seq_id: Hash128,
+        update_seq_id: bool,
+    ) -> Result<Hash128, InternalErr> {
+        let _notify = self.notify_on_return();
+        let events = self.events.read().named("events")?;
+        if id.0 >= events.len() {
+            return Err(InternalErr::InvalidEventId(id));
+        }
+        let mut event = events[id.0].write().named("event")?;
+        self.versioned_update(user, &mut *event, diff, seq_id, update_seq_id, |diff| {
+            Operation::EventMetadataUpdate {
+                id,
+                diff,
+                update_seq_id,
+            }
+        })
+        .map(|(seq_id, _)| seq_id)
+    }
+
+    pub fn track_add(
+        &self,
+        user: UserId,
+        release: ReleaseId,
+        track_num: TrackNum,
+        title: String,
+    ) -> Result<(), InternalErr> {
+        let _notify = self.notify_on_return();
+        let song = Song {
+            title,
+            seq_id: Hash128(0),
+            ..Default::default()
+        };
+        let track = TrackRef {
+            release_id: release,
+            track_num,
+        };
+        let releases = self.releases.read().named("releases")?;
+        let mut release = releases
+            .get(release.0)
+            .ok_or(InternalErr::InvalidReleaseId(release))?
+            .write()
+            .named("release")?;
+        if release.tracks.contains_key(&track_num) {
+            return Err(InternalErr::InvalidTrackRef(track));
+        }
+        self.record(
+            user,
+            &Operation::TrackAdd {
+                track,
+                song: song.clone(),
+            },
+        )?;
+        let artists = derived::track_artists(&song.artists, &release.album_artists);
+        release.tracks.insert(track_num, song);
+        self.index_track_artists(track, &HashSet::new(), &artists)
+    }
+
+    pub fn track_update(
+        &self,
+        user: UserId,
+        release: ReleaseId,
+        track_num: TrackNum,
+        mut diff: SongDiff,
+        seq_id: Hash128,
+        update_seq_id: bool,
+    ) -> Result<Hash128, InternalErr> {
+        let _notify = self.notify_on_return();
+        // recorded normalized, so the seq_id does not depend on the order the client sent
+        if let SongDiff::Credits(credits) = &mut diff {
+            normalize_credits(credits);
+        }
+        if let SongDiff::Tags(tags) = &diff {
+            self.check_tags(tags)?;
         }
-        self.wal.record(user, "artist_metadata_update", &diff)?;
-        apply_artist_meta_data_diff(&mut artist, diff);
+        let track = TrackRef {
+            release_id: release,
+            track_num,
+        };
+        // the originals have to exist, like in `song_relation_add`
+        // looked up before the release is locked, see `existing_tracks`
+        if let SongDiff::Originals(originals) = &diff {
+            let existing = self.existing_tracks(originals.iter().map(|(original, _)| *original))?;
+            if let Some((original, _)) = originals
+                .iter()
+                .find(|(original, _)| !existing.contains(original))
+            {
+                return Err(InternalErr::InvalidTrackRef(*original));
+            }
+        }
+        let releases = self.releases.read().named("releases")?;
+        let mut release = releases
+            .get(release.0)
+            .ok_or(InternalErr::InvalidReleaseId(release))?
+            .write()
+            .named("release")?;
+        let release = &mut *release;
+        let song = release
+            .tracks
+            .get_mut(&track_num)
+            .ok_or(InternalErr::InvalidTrackRef(track))?;
+        let (artists, originals) = (song.artists.clone(), song.originals.clone());
+        let (seq_id, _) =
+            self.versioned_update(user, song, diff, seq_id, update_seq_id, |diff| {
+                Operation::TrackUpdate {
+                    track,
+                    diff,
+                    update_seq_id,
+                }
+            })?;
+        self.index_track_artists(
+            track,
+            &derived::track_artists(&artists, &release.album_artists),
+            &derived::track_artists(&song.artists, &release.album_artists),
+        )?;
+        self.index_originals(track, &originals, &song.originals)?;
         Ok(seq_id)
     }
+
+    // shared by the sequential update methods, the caller holds the entity write lock
+    // `op` makes the operation to record from the diff
+    fn versioned_update<T: Versioned>(
+        &self,
+        user: UserId,
+        entity: &mut T,
+        diff: T::Diff,
+        mut seq_id: Hash128,
+        update_seq_id: bool,
+        op: impl FnOnce(T::Diff) -> Operation,
+    ) -> Result<(Hash128, T::Diff), InternalErr> {
+        // a diff that changes nothing, e.g. a retry of an applied update, is not recorded and
+        // keeps the seq_id, even if the client's seq_id is outdated by the first attempt
+        if entity.is_noop(&diff) {
+            return Ok((entity.seq_id(), diff));
+        }
+        let hash = get_hash(&diff);
+        // enforce sequential update for each entity
+        if entity.seq_id() != seq_id {
+            return Err(InternalErr::OutdatedUpdate {
+                current: entity.seq_id(),
+            });
+        }
+        // also rejects diffs that cannot be applied, before anything is recorded
+        let inverse = entity.invert_diff(&diff)?;
+        if update_seq_id {
+            seq_id = next_seq_id(seq_id, hash, user);
+            *entity.seq_id_mut() = seq_id;
+        }
+        self.record(user, &op(diff.clone()))?;
+        entity.apply_diff(diff)?;
+        Ok((seq_id, inverse))
+    }
+}
+
+// whether the originals of `release`, about to be added as `id`, form a cycle among its own
+// tracks; originals in other releases cannot lead back to it
+fn has_own_cycle(id: ReleaseId, release: &Release) -> bool {
+    // `false` while the track is on the current path, `true` once all its originals are done
+    fn visit(
+        id: ReleaseId,
+        release: &Release,
+        track_num: TrackNum,
+        done: &mut HashMap<TrackNum, bool>,
+    ) -> bool {
+        match done.get(&track_num) {
+            Some(finished) => return !finished,
+            None => done.insert(track_num, false),
+        };
+        let cycle = release.tracks[&track_num]
+            .originals
+            .iter()
+            .filter(|(original, _)| original.release_id == id)
+            .any(|(original, _)| visit(id, release, original.track_num, done));
+        done.insert(track_num, true);
+        cycle
+    }
+    let mut done = HashMap::new();
+    release
+        .tracks
+        .keys()
+        .any(|track_num| visit(id, release, *track_num, &mut done))
+}
+
+// replaces `from` with `into` in `items`, dropping entries that become duplicates
+fn repoint<T: PartialEq>(
+    items: &mut Vec<T>,
+    artist: impl Fn(&mut T) -> &mut ArtistId,
+    from: ArtistId,
+    into: ArtistId,
+) {
+    let mut repointed: Vec<T> = Vec::with_capacity(items.len());
+    for mut item in items.drain(..) {
+        let id = artist(&mut item);
+        if *id == from {
+            *id = into;
+        }
+        if !repointed.contains(&item) {
+            repointed.push(item);
+        }
+    }
+    *items = repointed;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::wal::NaiveLogStore;
+    use super::*;
+
+    #[test]
+    fn capacity_is_reserved() {
+        let wal = NaiveLogStore::new();
+        let states = States::with_capacity(&wal, 100, 20, 10);
+        assert!(states.artists.read().unwrap().capacity() >= 100);
+        assert!(states.releases.read().unwrap().capacity() >= 20);
+        assert!(states.events.read().unwrap().capacity() >= 10);
+
+        for i in 0..3 {
+            states.artist_add(UserId(1), format!("artist {i}")).unwrap();
+        }
+        states.reserve_artists(500).unwrap();
+        let capacity = states.artists.read().unwrap().capacity();
+        assert!(capacity >= 503);
+        // adding within the reserved capacity does not allocate
+        for i in 0..500 {
+            states.artist_add(UserId(1), format!("more {i}")).unwrap();
+        }
+        assert_eq!(states.artists.read().unwrap().capacity(), capacity);
+    }
+
+    #[test]
+    fn updates_only_set_existing_tags() {
+        let wal = NaiveLogStore::new();
+        let states = States::new(&wal);
+        let user = UserId(1);
+        let artist = states.artist_add(user, "artist".to_owned()).unwrap();
+        let release = states.release_add(user, "release".to_owned()).unwrap();
+        let track_num = TrackNum {
+            disc_num: 0,
+            track_num: 1,
+        };
+        states
+            .track_add(user, release, track_num, "track".to_owned())
+            .unwrap();
+        let update = |tags: Vec<TagId>| {
+            let seq_id = states.get_artist(artist).unwrap().seq_id;
+            let diff = ArtistMetaDataDiff::Tags(tags.clone());
+            states.artist_metadata_update(user, artist, diff, seq_id, true)?;
+            let seq_id = states.get_release(release).unwrap().seq_id;
+            let diff = ReleaseDiff::Tags(tags.clone());
+            states.release_metadata_update(user, release, diff, seq_id, true)?;
+            let track = TrackRef {
+                release_id: release,
+                track_num,
+            };
+            let seq_id = states.resolve_track(track).unwrap().seq_id;
+            let diff = SongDiff::Tags(tags);
+            states.track_update(user, release, track_num, diff, seq_id, true)
+        };
+        assert_eq!(
+            update(vec![TagId(0)]),
+            Err(InternalErr::InvalidTagId(TagId(0)))
+        );
+        let tag = states.tag_add(user, "tag".to_owned()).unwrap();
+        update(vec![tag]).unwrap();
+        assert_eq!(states.get_artist(artist).unwrap().tags, [tag]);
+        assert_eq!(states.get_release(release).unwrap().tags, [tag]);
+    }
+
+    #[test]
+    fn batch_updates_are_all_or_nothing() {
+        let wal = NaiveLogStore::new();
+        let states = States::new(&wal);
+        let user = UserId(1);
+        let artist = states.artist_add(user, "artist".to_owned()).unwrap();
+        let before = states.get_artist(artist).unwrap();
+        let records = || wal.read_all().unwrap().len();
+        let recorded = records();
+
+        let failing = ArtistMetaDataDiffSet(vec![
+            ArtistMetaDataDiff::Name("renamed".to_owned()),
+            ArtistMetaDataDiff::Aliases(VecOp::RemoveAt(0)),
+        ]);
+        assert_eq!(
+            states.artist_metadata_update_batch(user, artist, failing, before.seq_id, true),
+            Err(InternalErr::IndexOutOfBounds(0))
+        );
+        assert_eq!(states.get_artist(artist).unwrap(), before);
+        assert_eq!(records(), recorded);
+
+        let diffs = ArtistMetaDataDiffSet(vec![
+            ArtistMetaDataDiff::Name("renamed".to_owned()),
+            ArtistMetaDataDiff::Birthyear(Some(2000)),
+        ]);
+        // serialized as a plain list of diffs
+        assert_eq!(
+            serde_json::to_string(&diffs).unwrap(),
+            r#"[{"Name":"renamed"},{"Birthyear":2000}]"#
+        );
+        let seq_id = states
+            .artist_metadata_update_batch(user, artist, diffs, before.seq_id, true)
+            .unwrap();
+        let after = states.get_artist(artist).unwrap();
+        assert_eq!(
+            (after.name.as_str(), after.birthyear),
+            ("renamed", Some(2000))
+        );
+        assert_eq!(after.seq_id, seq_id);
+        assert_ne!(seq_id, before.seq_id);
+        assert_eq!(records(), recorded + 1);
+    }
+
+    #[test]
+    fn no_op_updates_are_not_recorded() {
+        let wal = NaiveLogStore::new();
+        let states = States::new(&wal);
+        let user = UserId(1);
+        let artist = states.artist_add(user, "artist".to_owned()).unwrap();
+        let first = states.get_artist(artist).unwrap().seq_id;
+        let rename = || ArtistMetaDataDiff::Name("renamed".to_owned());
+        let second = states
+            .artist_metadata_update(user, artist, rename(), first, true)
+            .unwrap();
+        let recorded = wal.read_all().unwrap().len();
+
+        // a retry with the seq_id from before the first attempt
+        assert_eq!(
+            states.artist_metadata_update(user, artist, rename(), first, true),
+            Ok(second)
+        );
+        let batch = ArtistMetaDataDiffSet(vec![rename(), ArtistMetaDataDiff::Birthyear(None)]);
+        assert_eq!(
+            states.artist_metadata_update_batch(user, artist, batch, first, true),
+            Ok(second)
+        );
+        let descriptions =
+            ArtistMetaDataDiff::Descriptions(MapOp::Remove(LocalId::parse("en").unwrap()));
+        assert_eq!(
+            states.artist_metadata_update(user, artist, descriptions, first, true),
+            Ok(second)
+        );
+        assert_eq!(wal.read_all().unwrap().len(), recorded);
+        assert_eq!(states.get_artist(artist).unwrap().seq_id, second);
+
+        // a change still needs the current seq_id
+        let diff = ArtistMetaDataDiff::Birthyear(Some(2000));
+        assert_eq!(
+            states.artist_metadata_update(user, artist, diff, first, true),
+            Err(InternalErr::OutdatedUpdate { current: second })
+        );
+    }
 }
@@ -0,0 +1,461 @@
+// Typed WAL operations
+//
+// Every mutation of `States` is recorded as one `Operation`. In the WAL, an operation is stored
+// as its api name and a JSON payload, which keeps the format of the records written before this
+// type existed: `parse` reads them back, `payload` writes them.
+
+use super::defs::*;
+use super::errors::InternalErr;
+use super::hashes::{Hash128, get_hash};
+use serde::{Serialize, Serializer};
+use std::fmt;
+use std::str::FromStr;
+
+// defines `ApiName` from the pairs of variant and name as stored in the WAL
+macro_rules! api_names {
+    ($($variant:ident => $name:literal,)*) => {
+        #[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+        pub enum ApiName {
+            $($variant,)*
+        }
+
+        impl ApiName {
+            pub fn as_str(self) -> &'static str {
+                match self {
+                    $(ApiName::$variant => $name,)*
+                }
+            }
+        }
+
+        impl FromStr for ApiName {
+            type Err = InternalErr;
+
+            fn from_str(s: &str) -> Result<ApiName, InternalErr> {
+                match s {
+                    $($name => Ok(ApiName::$variant),)*
+                    _ => Err(InternalErr::Other(format!("unknown api_name: {s}"))),
+                }
+            }
+        }
+    };
+}
+
+// the name of each kind of operation, the only place the names are spelled out
+api_names! {
+    ArtistAdd => "artist_add",
+    ArtistRemove => "artist_remove",
+    ArtistMerge => "artist_merge",
+    MembershipAdd => "membership_add",
+    MembershipRemove => "membership_remove",
+    ReleaseAdd => "release_add",
+    EventAdd => "event_add",
+    TagAdd => "tag_add",
+    LocationAdd => "location_add",
+    ArtistMetadataUpdate => "artist_metadata_update",
+    ArtistMetadataUpdateBatch => "artist_metadata_update_batch",
+    ArtistMetadataReplace => "artist_metadata_replace",
+    ReleaseMetadataUpdate => "release_metadata_update",
+    EventMetadataUpdate => "event_metadata_update",
+    TrackAdd => "track_add",
+    TrackUpdate => "track_update",
+    SongRelationAdd => "song_relation_add",
+    UrlArchivedSet => "url_archived_set",
+    ProfileImageSet => "profile_image_set",
+    LyricsAttach => "lyrics_attach",
+    LyricsDetach => "lyrics_detach",
+    CoverArtSet => "cover_art_set",
+    ReleaseImageAdd => "release_image_add",
+}
+
+impl fmt::Display for ApiName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Operation {
+    ArtistAdd(ArtistMetaData),
+    ArtistRemove(ArtistId),
+    ArtistMerge {
+        from: ArtistId,
+        into: ArtistId,
+    },
+    MembershipAdd {
+        member: ArtistId,
+        membership: ArtistMembership,
+    },
+    MembershipRemove {
+        member: ArtistId,
+        group: ArtistId,
+    },
+    ReleaseAdd(Release),
+    EventAdd(Event),
+    TagAdd(String),
+    LocationAdd {
+        id: LocationId,
+        name: String,
+    },
+    ArtistMetadataUpdate {
+        id: ArtistId,
+        diff: ArtistMetaDataDiff,
+        update_seq_id: bool,
+    },
+    ArtistMetadataUpdateBatch {
+        id: ArtistId,
+        diffs: ArtistMetaDataDiffSet,
+        update_seq_id: bool,
+    },
+    ArtistMetadataReplace {
+        id: ArtistId,
+        artist: ArtistMetaData,
+        replace_skip_diff: bool,
+        update_seq_id: bool,
+    },
+    ReleaseMetadataUpdate {
+        id: ReleaseId,
+        diff: ReleaseDiff,
+        update_seq_id: bool,
+    },
+    EventMetadataUpdate {
+        id: EventId,
+        diff: EventDiff,
+        update_seq_id: bool,
+    },
+    TrackAdd {
+        track: TrackRef,
+        song: Song,
+    },
+    TrackUpdate {
+        track: TrackRef,
+        diff: SongDiff,
+        update_seq_id: bool,
+    },
+    SongRelationAdd {
+        derived: TrackRef,
+        original: TrackRef,
+        kind: SongRelationKind,
+    },
+    UrlArchivedSet {
+        owner: EntityRef,
+        index: usize,
+        archived: String,
+    },
+    ProfileImageSet {
+        id: ArtistId,
+        image: Option<Image>,
+    },
+    LyricsAttach {
+        track: TrackRef,
+        local: LocalId,
+        file: FileId,
+    },
+    LyricsDetach {
+        track: TrackRef,
+        local: LocalId,
+    },
+    CoverArtSet {
+        id: ReleaseId,
+        image: Image,
+    },
+    ReleaseImageAdd {
+        id: ReleaseId,
+        image: Image,
+    },
+}
+
+// the JSON payload of an operation, see `Operation::payload`
+pub struct OperationPayload<'a>(&'a Operation);
+
+impl Serialize for OperationPayload<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self.0 {
+            Operation::ArtistAdd(artist) => artist.serialize(serializer),
+            Operation::ArtistRemove(id) => id.serialize(serializer),
+            Operation::ArtistMerge { from, into } => (from, into).serialize(serializer),
+            Operation::MembershipAdd { member, membership } => {
+                (member, membership).serialize(serializer)
+            }
+            Operation::MembershipRemove { member, group } => (member, group).serialize(serializer),
+            Operation::ReleaseAdd(release) => release.serialize(serializer),
+            Operation::EventAdd(event) => event.serialize(serializer),
+            Operation::TagAdd(name) => name.serialize(serializer),
+            Operation::LocationAdd { id, name } => (id, name).serialize(serializer),
+            Operation::ArtistMetadataUpdate {
+                id,
+                diff,
+                update_seq_id,
+            } => (id, diff, update_seq_id).serialize(serializer),
+            Operation::ArtistMetadataUpdateBatch {
+                id,
+                diffs,
+                update_seq_id,
+            } => (id, diffs, update_seq_id).serialize(serializer),
+            Operation::ArtistMetadataReplace {
+                id,
+                artist,
+                replace_skip_diff,
+                update_seq_id,
+            } => (id, artist, replace_skip_diff, update_seq_id).serialize(serializer),
+            Operation::ReleaseMetadataUpdate {
+                id,
+                diff,
+                update_seq_id,
+            } => (id, diff, update_seq_id).serialize(serializer),
+            Operation::EventMetadataUpdate {
+                id,
+                diff,
+                update_seq_id,
+            } => (id, diff, update_seq_id).serialize(serializer),
+            Operation::TrackAdd { track, song } => (track, song).serialize(serializer),
+            Operation::TrackUpdate {
+                track,
+                diff,
+                update_seq_id,
+            } => (track, diff, update_seq_id).serialize(serializer),
+            Operation::SongRelationAdd {
+                derived,
+                original,
+                kind,
+            } => (derived, original, kind).serialize(serializer),
+            Operation::UrlArchivedSet {
+                owner,
+                index,
+                archived,
+            } => (owner, index, archived).serialize(serializer),
+            Operation::ProfileImageSet { id, image } => (id, image).serialize(serializer),
+            Operation::LyricsAttach { track, local, file } => {
+                (track, local, file).serialize(serializer)
+            }
+            Operation::LyricsDetach { track, local } => (track, local).serialize(serializer),
+            Operation::CoverArtSet { id, image } | Operation::ReleaseImageAdd { id, image } => {
+                (id, image).serialize(serializer)
+            }
+        }
+    }
+}
+
+fn from_json<T: serde::de::DeserializeOwned>(json: &str) -> Result<T, InternalErr> {
+    serde_json::from_str(json).map_err(|e| InternalErr::Other(e.to_string()))
+}
+
+impl Operation {
+    pub fn api_name(&self) -> ApiName {
+        match self {
+            Operation::ArtistAdd(_) => ApiName::ArtistAdd,
+            Operation::ArtistRemove(_) => ApiName::ArtistRemove,
+            Operation::ArtistMerge { .. } => ApiName::ArtistMerge,
+            Operation::MembershipAdd { .. } => ApiName::MembershipAdd,
+            Operation::MembershipRemove { .. } => ApiName::MembershipRemove,
+            Operation::ReleaseAdd(_) => ApiName::ReleaseAdd,
+            Operation::EventAdd(_) => ApiName::EventAdd,
+            Operation::TagAdd(_) => ApiName::TagAdd,
+            Operation::LocationAdd { .. } => ApiName::LocationAdd,
+            Operation::ArtistMetadataUpdate { .. } => ApiName::ArtistMetadataUpdate,
+            Operation::ArtistMetadataUpdateBatch { .. } => ApiName::ArtistMetadataUpdateBatch,
+            Operation::ArtistMetadataReplace { .. } => ApiName::ArtistMetadataReplace,
+            Operation::ReleaseMetadataUpdate { .. } => ApiName::ReleaseMetadataUpdate,
+            Operation::EventMetadataUpdate { .. } => ApiName::EventMetadataUpdate,
+            Operation::TrackAdd { .. } => ApiName::TrackAdd,
+            Operation::TrackUpdate { .. } => ApiName::TrackUpdate,
+            Operation::SongRelationAdd { .. } => ApiName::SongRelationAdd,
+            Operation::UrlArchivedSet { .. } => ApiName::UrlArchivedSet,
+            Operation::ProfileImageSet { .. } => ApiName::ProfileImageSet,
+            Operation::LyricsAttach { .. } => ApiName::LyricsAttach,
+            Operation::LyricsDetach { .. } => ApiName::LyricsDetach,
+            Operation::CoverArtSet { .. } => ApiName::CoverArtSet,
+            Operation::ReleaseImageAdd { .. } => ApiName::ReleaseImageAdd,
+        }
+    }
+
+    // the existing entities the operation changes or links, adds have none since the new id is
+    // not part of the payload
+    pub fn entities(&self) -> Vec<EntityRef> {
+        match self {
+            Operation::ArtistAdd(_)
+            | Operation::ReleaseAdd(_)
+            | Operation::EventAdd(_)
+            | Operation::TagAdd(_) => Vec::new(),
+            Operation::LocationAdd { id, .. } => vec![EntityRef::Location(*id)],
+            Operation::ArtistRemove(id) => vec![EntityRef::Artist(*id)],
+            Operation::ArtistMerge { from, into } => {
+                vec![EntityRef::Artist(*from), EntityRef::Artist(*into)]
+            }
+            Operation::MembershipAdd { member, membership } => vec![
+                EntityRef::Artist(*member),
+                EntityRef::Artist(membership.group_id),
+            ],
+            Operation::MembershipRemove { member, group } => {
+                vec![EntityRef::Artist(*member), EntityRef::Artist(*group)]
+            }
+            Operation::ArtistMetadataUpdate { id, .. }
+            | Operation::ArtistMetadataUpdateBatch { id, .. }
+            | Operation::ArtistMetadataReplace { id, .. } => vec![EntityRef::Artist(*id)],
+            Operation::ReleaseMetadataUpdate { id, .. } => vec![EntityRef::Release(*id)],
+            Operation::EventMetadataUpdate { id, .. } => vec![EntityRef::Event(*id)],
+            Operation::TrackAdd { track, .. } | Operation::TrackUpdate { track, .. } => {
+                vec![EntityRef::Track(*track)]
+            }
+            Operation::SongRelationAdd {
+                derived, original, ..
+            } => vec![EntityRef::Track(*derived), EntityRef::Track(*original)],
+            Operation::UrlArchivedSet { owner, .. } => vec![*owner],
+            Operation::ProfileImageSet { id, .. } => vec![EntityRef::Artist(*id)],
+            Operation::LyricsAttach { track, .. } | Operation::LyricsDetach { track, .. } => {
+                vec![EntityRef::Track(*track)]
+            }
+            Operation::CoverArtSet { id, .. } | Operation::ReleaseImageAdd { id, .. } => {
+                vec![EntityRef::Release(*id)]
+            }
+        }
+    }
+
+    // the fields changed by a metadata update, in diff order
+    // empty for a replace, the record does not say which fields differ from the old value, see
+    // `replace_artist_metadata`
+    pub fn fields(&self) -> Vec<&'static str> {
+        match self {
+            Operation::ArtistMetadataUpdate { diff, .. } => vec![diff.field_name()],
+            Operation::ArtistMetadataUpdateBatch { diffs, .. } => {
+                diffs.0.iter().map(|diff| diff.field_name()).collect()
+            }
+            Operation::ReleaseMetadataUpdate { diff, .. } => vec![diff.field_name()],
+            Operation::EventMetadataUpdate { diff, .. } => vec![diff.field_name()],
+            Operation::TrackUpdate { diff, .. } => vec![diff.field_name()],
+            Operation::UrlArchivedSet { .. } => vec!["urls"],
+            Operation::ProfileImageSet { .. } => vec!["profile_image"],
+            Operation::LyricsAttach { .. } | Operation::LyricsDetach { .. } => vec!["lyrics"],
+            Operation::CoverArtSet { .. } => vec!["cover_art", "images"],
+            Operation::ReleaseImageAdd { .. } => vec!["images"],
+            _ => Vec::new(),
+        }
+    }
+
+    pub fn payload(&self) -> OperationPayload<'_> {
+        OperationPayload(self)
+    }
+
+    // `get_hash` of the payload as the WAL records it, the diff hash of whole-artist replaces
+    // and of setters that bump a seq_id without taking one, see `verify_seq_chain`
+    pub fn payload_hash(&self) -> Result<Hash128, InternalErr> {
+        let json = serde_json::to_string(&self.payload())
+            .map_err(|e| InternalErr::Other(e.to_string()))?;
+        Ok(get_hash(&json))
+    }
+
+    // the operation of a WAL record
+    pub fn parse(api_name: &str, json: &str) -> Result<Operation, InternalErr> {
+        Ok(match api_name.parse()? {
+            ApiName::ArtistAdd => Operation::ArtistAdd(from_json(json)?),
+            ApiName::ArtistRemove => Operation::ArtistRemove(from_json(json)?),
+            ApiName::ArtistMerge => {
+                let (from, into) = from_json(json)?;
+                Operation::ArtistMerge { from, into }
+            }
+            ApiName::MembershipAdd => {
+                let (member, membership) = from_json(json)?;
+                Operation::MembershipAdd { member, membership }
+            }
+            ApiName::MembershipRemove => {
+                let (member, group) = from_json(json)?;
+                Operation::MembershipRemove { member, group }
+            }
+            ApiName::ReleaseAdd => Operation::ReleaseAdd(from_json(json)?),
+            ApiName::EventAdd => Operation::EventAdd(from_json(json)?),
+            ApiName::TagAdd => Operation::TagAdd(from_json(json)?),
+            ApiName::LocationAdd => {
+                let (id, name) = from_json(json)?;
+                Operation::LocationAdd { id, name }
+            }
+            ApiName::ArtistMetadataUpdate => {
+                let (id, diff, update_seq_id) = from_json(json)?;
+                Operation::ArtistMetadataUpdate {
+                    id,
+                    diff,
+                    update_seq_id,
+                }
+            }
+            ApiName::ArtistMetadataUpdateBatch => {
+                let (id, diffs, update_seq_id) = from_json(json)?;
+                Operation::ArtistMetadataUpdateBatch {
+                    id,
+                    diffs,
+                    update_seq_id,
+                }
+            }
+            ApiName::ArtistMetadataReplace => {
+                let (id, artist, replace_skip_diff, update_seq_id) = from_json(json)?;
+                Operation::ArtistMetadataReplace {
+                    id,
+                    artist,
+                    replace_skip_diff,
+                    update_seq_id,
+                }
+            }
+            ApiName::ReleaseMetadataUpdate => {
+                let (id, diff, update_seq_id) = from_json(json)?;
+                Operation::ReleaseMetadataUpdate {
+                    id,
+                    diff,
+                    update_seq_id,
+                }
+            }
+            ApiName::EventMetadataUpdate => {
+                let (id, diff, update_seq_id) = from_json(json)?;
+                Operation::EventMetadataUpdate {
+                    id,
+                    diff,
+                    update_seq_id,
+                }
+            }
+            ApiName::TrackAdd => {
+                let (track, song) = from_json(json)?;
+                Operation::TrackAdd { track, song }
+            }
+            ApiName::TrackUpdate => {
+                let (track, diff, update_seq_id) = from_json(json)?;
+                Operation::TrackUpdate {
+                    track,
+                    diff,
+                    update_seq_id,
+                }
+            }
+            ApiName::SongRelationAdd => {
+                let (derived, original, kind) = from_json(json)?;
+                Operation::SongRelationAdd {
+                    derived,
+                    original,
+                    kind,
+                }
+            }
+            ApiName::UrlArchivedSet => {
+                let (owner, index, archived) = from_json(json)?;
+                Operation::UrlArchivedSet {
+                    owner,
+                    index,
+                    archived,
+                }
+            }
+            ApiName::ProfileImageSet => {
+                let (id, image) = from_json(json)?;
+                Operation::ProfileImageSet { id, image }
+            }
+            ApiName::LyricsAttach => {
+                let (track, local, file) = from_json(json)?;
+                Operation::LyricsAttach { track, local, file }
+            }
+            ApiName::LyricsDetach => {
+                let (track, local) = from_json(json)?;
+                Operation::LyricsDetach { track, local }
+            }
+            ApiName::CoverArtSet => {
+                let (id, image) = from_json(json)?;
+                Operation::CoverArtSet { id, image }
+            }
+            ApiName::ReleaseImageAdd => {
+                let (id, image) = from_json(json)?;
+                Operation::ReleaseImageAdd { id, image }
+            }
+        })
+    }
+}
@@ -0,0 +1,71 @@
+// Per-user rules for mutations
+//
+// Every mutation asks the policy of the states before it records anything, with the api_name of
+// the record it is about to write, e.g. `ApiName::ArtistAdd`. An error from the policy is
+// returned as is and leaves the states and the WAL untouched. Replay does not consult the policy,
+// the records were already allowed when they were written.
+
+use super::States;
+use super::defs::UserId;
+use super::errors::InternalErr;
+use super::operation::ApiName;
+use super::wal::LogStore;
+
+// shared by all threads using the states, so it has to be `Sync`
+pub trait EditPolicy: Sync {
+    fn check(&self, user: UserId, api_name: ApiName) -> Result<(), InternalErr>;
+}
+
+// allows everything, the policy of new states
+pub struct AllowAll;
+
+impl EditPolicy for AllowAll {
+    fn check(&self, _user: UserId, _api_name: ApiName) -> Result<(), InternalErr> {
+        Ok(())
+    }
+}
+
+impl<'a, L: LogStore> States<'a, L> {
+    pub fn with_edit_policy(self, policy: &'a dyn EditPolicy) -> Self {
+        States { policy, ..self }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::defs::*;
+    use super::super::wal::{LogStore, NaiveLogStore};
+    use super::*;
+
+    // only user 1 removes artists
+    struct Moderated;
+
+    impl EditPolicy for Moderated {
+        fn check(&self, user: UserId, api_name: ApiName) -> Result<(), InternalErr> {
+            match api_name {
+                ApiName::ArtistRemove if user != UserId(1) => {
+                    Err(InternalErr::Other("not a moderator".to_owned()))
+                }
+                _ => Ok(()),
+            }
+        }
+    }
+
+    #[test]
+    fn rejected_mutations_change_nothing() {
+        let wal = NaiveLogStore::new();
+        let states = States::new(&wal).with_edit_policy(&Moderated);
+        let artist = states.artist_add(UserId(2), "artist".to_owned()).unwrap();
+        let before = states.snapshot().unwrap();
+        let recorded = wal.read_all().unwrap().len();
+        assert_eq!(
+            states.artist_remove(UserId(2), artist),
+            Err(InternalErr::Other("not a moderator".to_owned()))
+        );
+        assert_eq!(states.snapshot().unwrap(), before);
+        assert_eq!(wal.read_all().unwrap().len(), recorded);
+
+        states.artist_remove(UserId(1), artist).unwrap();
+        assert!(states.get_artist(artist).is_err());
+    }
+}
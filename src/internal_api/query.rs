@@ -0,0 +1,422 @@
+// Read-only queries over `States`
+
+use super::States;
+use super::defs::*;
+use super::errors::{InternalErr, LockName};
+use super::wal::LogStore;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum NameMatch {
+    // for dedup: the normalized names are equal
+    Exact,
+    // for autocomplete
+    Prefix,
+    Substring,
+}
+
+fn normalize_name(name: &str) -> String {
+    name.trim().to_lowercase()
+}
+
+impl NameMatch {
+    fn matches(self, name: &str, query: &str) -> bool {
+        let name = normalize_name(name);
+        match self {
+            NameMatch::Exact => name == query,
+            NameMatch::Prefix => name.starts_with(query),
+            NameMatch::Substring => name.contains(query),
+        }
+    }
+}
+
+// every version of a song, see `song_family`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SongFamily {
+    pub root: TrackRef,
+    // the tracks derived from the root, directly or not, by the kind of their own relation
+    pub versions: HashMap<SongRelationKind, Vec<TrackRef>>,
+}
+
+// tracks without a duration count as 0, `None` if no track has one
+fn total_duration<'s>(songs: impl Iterator<Item = &'s Song>) -> Option<u32> {
+    songs
+        .filter_map(|song| song.duration_s)
+        .fold(None, |total, duration| {
+            Some(total.unwrap_or(0u32).saturating_add(duration))
+        })
+}
+
+impl<'a, L: LogStore> States<'a, L> {
+    // matches against the artist name and all aliases, ignoring case and surrounding whitespace
+    pub fn find_artists_by_name(
+        &self,
+        query: &str,
+        mode: NameMatch,
+    ) -> Result<Vec<ArtistId>, InternalErr> {
+        let query = normalize_name(query);
+        let mut found = Vec::new();
+        let artists = self.artists.read().named("artists")?;
+        for (i, artist) in artists.iter().enumerate() {
+            let artist = artist.read().named("artist")?;
+            let Some(artist) = artist.as_ref() else {
+                continue;
+            };
+            if mode.matches(&artist.name, &query)
+                || artist
+                    .aliases
+                    .iter()
+                    .any(|alias| mode.matches(&alias.content, &query))
+            {
+                found.push(ArtistId(i));
+            }
+        }
+        Ok(found)
+    }
+
+    // names of `ids` in the given order, under a single lock of the artists
+    // invalid or removed artists are left out if `skip_invalid`, otherwise they are an error
+    pub fn artist_names(
+        &self,
+        ids: &[ArtistId],
+        skip_invalid: bool,
+    ) -> Result<Vec<(ArtistId, String)>, InternalErr> {
+        let artists = self.artists.read().named("artists")?;
+        let mut names = Vec::with_capacity(ids.len());
+        for id in ids.iter() {
+            let name = match artists.get(id.0) {
+                Some(artist) => artist
+                    .read()
+                    .named("artist")?
+                    .as_ref()
+                    .map(|artist| artist.name.clone()),
+                None => None,
+            };
+            match name {
+                Some(name) => names.push((*id, name)),
+                None if skip_invalid => {}
+                None => return Err(InternalErr::InvalidArtistId(*id)),
+            }
+        }
+        Ok(names)
+    }
+
+    // releases where the artist is an album artist, credited, or an artist or credit of a track,
+    // in release id order
+    pub fn releases_for_artist(&self, id: ArtistId) -> Result<Vec<ReleaseId>, InternalErr> {
+        let mut found = Vec::new();
+        let releases = self.releases.read().named("releases")?;
+        for (i, release) in releases.iter().enumerate() {
+            let release = release.read().named("release")?;
+            let credited = |credits: &[(ArtistId, ArtistRole)]| credits.iter().any(|c| c.0 == id);
+            if release.album_artists.contains(&id)
+                || credited(&release.credits)
+                || release
+                    .tracks
+                    .values()
+                    .any(|song| song.artists.contains(&id) || credited(&song.credits))
+            {
+                found.push(ReleaseId(i));
+            }
+        }
+        Ok(found)
+    }
+
+    // Follows `Song::originals` from `track` to a track without originals. At each step, a
+    // `Cover` or `ReRelease` edge is preferred over the other kinds, and among the preferred
+    // edges the original with the lowest `TrackRef`, i.e. the lowest release id, is taken, so the
+    // result does not depend on the order of `originals`. A cycle is `InvalidRelation`.
+    pub fn resolve_original(&self, track: TrackRef) -> Result<TrackRef, InternalErr> {
+        let releases = self.releases.read().named("releases")?;
+        let mut visited = HashSet::new();
+        let mut current = track;
+        loop {
+            if !visited.insert(current) {
+                return Err(InternalErr::InvalidRelation);
+            }
+            let release = releases
+                .get(current.release_id.0)
+                .ok_or(InternalErr::InvalidTrackRef(current))?
+                .read()
+                .named("release")?;
+            let song = release
+                .tracks
+                .get(&current.track_num)
+                .ok_or(InternalErr::InvalidTrackRef(current))?;
+            let preferred = |kind: &SongRelationKind| {
+                matches!(kind, SongRelationKind::Cover | SongRelationKind::ReRelease)
+            };
+            let next = song
+                .originals
+                .iter()
+                .filter(|(_, kind)| preferred(kind))
+                .map(|(original, _)| *original)
+                .min()
+                .or_else(|| song.originals.iter().map(|(original, _)| *original).min());
+            match next {
+                Some(next) => current = next,
+                None => return Ok(current),
+            }
+        }
+    }
+
+    // The original of `track`, see `resolve_original`, and every track derived from it through
+    // `derived_songs`. A track is listed once, under the kind of the relation it was first
+    // reached by, going breadth-first and in index order, and each list is sorted. The root is
+    // never listed, even if a cycle leads back to it.
+    pub fn song_family(&self, track: TrackRef) -> Result<SongFamily, InternalErr> {
+        let root = self.resolve_original(track)?;
+        let derived_songs = self.derived_songs.read().named("derived_songs")?;
+        let mut versions: HashMap<SongRelationKind, Vec<TrackRef>> = HashMap::new();
+        let mut visited = HashSet::from([root]);
+        let mut queue = VecDeque::from([root]);
+        while let Some(current) = queue.pop_front() {
+            for (derived, kind) in derived_songs.get(&current).into_iter().flatten() {
+                if visited.insert(*derived) {
+                    versions.entry(*kind).or_default().push(*derived);
+                    queue.push_back(*derived);
+                }
+            }
+        }
+        for tracks in versions.values_mut() {
+            tracks.sort();
+        }
+        Ok(SongFamily { root, versions })
+    }
+
+    // releases whose own `tags` contain all of `required` and, unless `any` is empty, at least one
+    // of `any`, in release id order
+    pub fn releases_with_tags(
+        &self,
+        required: &[TagId],
+        any: &[TagId],
+    ) -> Result<Vec<ReleaseId>, InternalErr> {
+        Ok(self
+            .tagged_releases(required, any)?
+            .into_iter()
+            .map(|(id, _)| id)
+            .collect())
+    }
+
+    // for a facet sidebar: how many of the releases matching `releases_with_tags` have each tag,
+    // in tag id order
+    pub fn release_tag_counts(
+        &self,
+        required: &[TagId],
+        any: &[TagId],
+    ) -> Result<Vec<(TagId, usize)>, InternalErr> {
+        let mut counts: BTreeMap<TagId, usize> = BTreeMap::new();
+        for (_, tags) in self.tagged_releases(required, any)? {
+            for tag in tags {
+                *counts.entry(tag).or_default() += 1;
+            }
+        }
+        Ok(counts.into_iter().collect())
+    }
+
+    // matching releases with their tags, each tag once
+    fn tagged_releases(
+        &self,
+        required: &[TagId],
+        any: &[TagId],
+    ) -> Result<Vec<(ReleaseId, HashSet<TagId>)>, InternalErr> {
+        let mut found = Vec::new();
+        for (i, release) in self.releases.read().named("releases")?.iter().enumerate() {
+            let tags: HashSet<TagId> = release
+                .read()
+                .named("release")?
+                .tags
+                .iter()
+                .copied()
+                .collect();
+            if required.iter().all(|tag| tags.contains(tag))
+                && (any.is_empty() || any.iter().any(|tag| tags.contains(tag)))
+            {
+                found.push((ReleaseId(i), tags));
+            }
+        }
+        Ok(found)
+    }
+
+    // total runtime in seconds, see `total_duration`
+    pub fn release_total_duration(&self, id: ReleaseId) -> Result<Option<u32>, InternalErr> {
+        let releases = self.releases.read().named("releases")?;
+        let release = releases
+            .get(id.0)
+            .ok_or(InternalErr::InvalidReleaseId(id))?
+            .read()
+            .named("release")?;
+        Ok(total_duration(release.tracks.values()))
+    }
+
+    // runtime of each disc that has tracks, in disc order
+    pub fn release_disc_durations(
+        &self,
+        id: ReleaseId,
+    ) -> Result<Vec<(u16, Option<u32>)>, InternalErr> {
+        let releases = self.releases.read().named("releases")?;
+        let release = releases
+            .get(id.0)
+            .ok_or(InternalErr::InvalidReleaseId(id))?
+            .read()
+            .named("release")?;
+        let mut discs: BTreeMap<u16, Vec<&Song>> = BTreeMap::new();
+        for (track_num, song) in release.tracks.iter() {
+            discs.entry(track_num.disc_num).or_default().push(song);
+        }
+        Ok(discs
+            .into_iter()
+            .map(|(disc, songs)| (disc, total_duration(songs.into_iter())))
+            .collect())
+    }
+
+    // urls without an archived copy, by owner and index in its `urls`, artists first, then
+    // releases, then events, each in id order
+    pub fn urls_missing_archive(&self) -> Result<Vec<(EntityRef, usize)>, InternalErr> {
+        let missing = |urls: &[Url]| {
+            urls.iter()
+                .enumerate()
+                .filter(|(_, url)| url.archived.is_none())
+                .map(|(i, _)| i)
+                .collect::<Vec<_>>()
+        };
+        let mut found = Vec::new();
+        for (i, artist) in self.artists.read().named("artists")?.iter().enumerate() {
+            if let Some(artist) = artist.read().named("artist")?.as_ref() {
+                let owner = EntityRef::Artist(ArtistId(i));
+                found.extend(missing(&artist.urls).into_iter().map(|j| (owner, j)));
+            }
+        }
+        for (i, release) in self.releases.read().named("releases")?.iter().enumerate() {
+            let owner = EntityRef::Release(ReleaseId(i));
+            let urls = missing(&release.read().named("release")?.urls);
+            found.extend(urls.into_iter().map(|j| (owner, j)));
+        }
+        for (i, event) in self.events.read().named("events")?.iter().enumerate() {
+            let owner = EntityRef::Event(EventId(i));
+            let urls = missing(&event.read().named("event")?.urls);
+            found.extend(urls.into_iter().map(|j| (owner, j)));
+        }
+        Ok(found)
+    }
+
+    // Listing pages: `offset` and `limit` are in ids rather than in listed entities, so removing
+    // an artist does not shift the later pages. A page of artists can thus have fewer than
+    // `limit` entries, and the next page starts at `offset + limit`.
+    pub fn list_artists(
+        &self,
+        offset: usize,
+        limit: usize,
+    ) -> Result<Vec<(ArtistId, String)>, InternalErr> {
+        let artists = self.artists.read().named("artists")?;
+        let mut page = Vec::new();
+        for i in offset..offset.saturating_add(limit).min(artists.len()) {
+            if let Some(artist) = artists[i].read().named("artist")?.as_ref() {
+                page.push((ArtistId(i), artist.name.clone()));
+            }
+        }
+        Ok(page)
+    }
+
+    // artists that are not removed
+    pub fn count_artists(&self) -> Result<usize, InternalErr> {
+        let mut count = 0;
+        for artist in self.artists.read().named("artists")?.iter() {
+            count += usize::from(artist.read().named("artist")?.is_some());
+        }
+        Ok(count)
+    }
+
+    pub fn list_releases(
+        &self,
+        offset: usize,
+        limit: usize,
+    ) -> Result<Vec<(ReleaseId, String)>, InternalErr> {
+        let releases = self.releases.read().named("releases")?;
+        let mut page = Vec::new();
+        for i in offset..offset.saturating_add(limit).min(releases.len()) {
+            page.push((
+                ReleaseId(i),
+                releases[i].read().named("release")?.title.clone(),
+            ));
+        }
+        Ok(page)
+    }
+
+    pub fn count_releases(&self) -> Result<usize, InternalErr> {
+        Ok(self.releases.read().named("releases")?.len())
+    }
+
+    pub fn list_events(
+        &self,
+        offset: usize,
+        limit: usize,
+    ) -> Result<Vec<(EventId, String)>, InternalErr> {
+        let events = self.events.read().named("events")?;
+        let mut page = Vec::new();
+        for i in offset..offset.saturating_add(limit).min(events.len()) {
+            page.push((EventId(i), events[i].read().named("event")?.name.clone()));
+        }
+        Ok(page)
+    }
+
+    // events whose location is `loc`, in event id order
+    pub fn events_at_location(&self, loc: LocationId) -> Result<Vec<EventId>, InternalErr> {
+        let mut found = Vec::new();
+        for (i, event) in self.events.read().named("events")?.iter().enumerate() {
+            if event.read().named("event")?.location == Some(loc) {
+                found.push(EventId(i));
+            }
+        }
+        Ok(found)
+    }
+
+    pub fn count_events(&self) -> Result<usize, InternalErr> {
+        Ok(self.events.read().named("events")?.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::wal::NaiveLogStore;
+    use super::*;
+
+    #[test]
+    fn pages_are_in_ids() {
+        let wal = NaiveLogStore::new();
+        let states = States::new(&wal);
+        for i in 0..5 {
+            states.artist_add(UserId(1), format!("artist {i}")).unwrap();
+            states
+                .release_add(UserId(1), format!("release {i}"))
+                .unwrap();
+            states.event_add(UserId(1), format!("event {i}")).unwrap();
+        }
+        states.artist_remove(UserId(1), ArtistId(1)).unwrap();
+
+        let ids = |page: Vec<(ArtistId, String)>| page.into_iter().map(|a| a.0.0).collect();
+        let first: Vec<usize> = ids(states.list_artists(0, 2).unwrap());
+        assert_eq!(first, vec![0]);
+        let second: Vec<usize> = ids(states.list_artists(2, 2).unwrap());
+        assert_eq!(second, vec![2, 3]);
+        let last: Vec<usize> = ids(states.list_artists(4, 2).unwrap());
+        assert_eq!(last, vec![4]);
+        assert!(states.list_artists(6, 2).unwrap().is_empty());
+        assert_eq!(states.list_artists(1, usize::MAX).unwrap().len(), 3);
+        assert_eq!(states.count_artists().unwrap(), 4);
+
+        assert_eq!(
+            states.list_releases(3, 10).unwrap(),
+            vec![
+                (ReleaseId(3), "release 3".to_owned()),
+                (ReleaseId(4), "release 4".to_owned())
+            ]
+        );
+        assert_eq!(states.count_releases().unwrap(), 5);
+        assert_eq!(
+            states.list_events(0, 1).unwrap(),
+            vec![(EventId(0), "event 0".to_owned())]
+        );
+        assert_eq!(states.count_events().unwrap(), 5);
+    }
+}
@@ -0,0 +1,93 @@
+// Recovery from poisoned locks
+//
+// A lock is poisoned when a thread panics while holding it for writing, and every later access
+// then fails with `InternalErr::Poisoned`. The data behind it may be half updated, e.g. an entity
+// changed without its WAL record or the other way around, so `recover` should be followed by
+// `validate`, and by a replay from the WAL if the result is in doubt.
+
+use super::States;
+#[cfg(feature = "arc-swap")]
+use super::slot::Slot;
+use super::wal::LogStore;
+use std::sync::{Mutex, RwLock};
+
+fn clear<T>(lock: &RwLock<T>) -> usize {
+    let poisoned = lock.is_poisoned();
+    lock.clear_poison();
+    usize::from(poisoned)
+}
+
+fn clear_mutex<T>(lock: &Mutex<T>) -> usize {
+    let poisoned = lock.is_poisoned();
+    lock.clear_poison();
+    usize::from(poisoned)
+}
+
+#[cfg(not(feature = "arc-swap"))]
+use self::clear as clear_slot;
+
+#[cfg(feature = "arc-swap")]
+fn clear_slot<T: Clone>(slot: &Slot<T>) -> usize {
+    let poisoned = slot.is_poisoned();
+    slot.clear_poison();
+    usize::from(poisoned)
+}
+
+impl<'a, L: LogStore> States<'a, L> {
+    // clears the poison of every lock, returns how many were poisoned
+    pub fn recover(&self) -> usize {
+        let mut poisoned = clear(&self.artists) + clear(&self.releases) + clear(&self.events);
+        // the outer locks are not poisoned anymore
+        let artists = self.artists.read().unwrap_or_else(|e| e.into_inner());
+        let releases = self.releases.read().unwrap_or_else(|e| e.into_inner());
+        let events = self.events.read().unwrap_or_else(|e| e.into_inner());
+        poisoned += artists.iter().map(clear_slot).sum::<usize>();
+        poisoned += releases.iter().map(clear).sum::<usize>();
+        poisoned += events.iter().map(clear).sum::<usize>();
+        poisoned
+            + clear(&self.tags)
+            + clear(&self.locations)
+            + clear(&self.group_members)
+            + clear(&self.artist_discography)
+            + clear(&self.derived_songs)
+            + clear_mutex(&self.last_seq)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::defs::*;
+    use super::super::errors::InternalErr;
+    use super::super::wal::NaiveLogStore;
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn poisoned_locks_are_cleared() {
+        let wal = NaiveLogStore::new();
+        let states = States::new(&wal);
+        let release = states.release_add(UserId(1), "release".to_owned()).unwrap();
+        states.tag_add(UserId(1), "tag".to_owned()).unwrap();
+        let panicked = thread::scope(|s| {
+            s.spawn(|| {
+                let releases = states.releases.read().unwrap();
+                let _release = releases[release.0].write().unwrap();
+                let _tags = states.tags.write().unwrap();
+                panic!("poison");
+            })
+            .join()
+        });
+        assert!(panicked.is_err());
+        assert!(matches!(
+            states.get_release(release),
+            Err(InternalErr::Poisoned(_))
+        ));
+        assert!(states.tag_add(UserId(1), "other".to_owned()).is_err());
+
+        assert_eq!(states.recover(), 2);
+        assert_eq!(states.recover(), 0);
+        assert_eq!(states.get_release(release).unwrap().title, "release");
+        states.tag_add(UserId(1), "other".to_owned()).unwrap();
+        assert!(states.validate().is_empty());
+    }
+}
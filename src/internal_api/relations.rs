@@ -0,0 +1,232 @@
+// Relations between entities that are not part of the diffable metadata
+
+use super::States;
+use super::defs::*;
+use super::derived::{index, unindex};
+use super::errors::{InternalErr, LockName};
+use super::operation::Operation;
+use super::wal::LogStore;
+use std::collections::{HashMap, HashSet};
+
+// A group can only stop being a group once it has no members, any other change of kind is fine.
+// The caller holds `group_members` from this check until the new kind is applied, and
+// `membership_add` holds it from its check of the group until the member is indexed, so a member
+// cannot join a group while it stops being one. `group_members` is locked after the outer
+// artists lock and before any artist, by every writer that takes both.
+pub(super) fn check_kind_change(
+    group_members: &HashMap<ArtistId, Vec<ArtistId>>,
+    id: ArtistId,
+    from: Option<ArtistKind>,
+    to: Option<ArtistKind>,
+) -> Result<(), InternalErr> {
+    if from == Some(ArtistKind::Group)
+        && to != Some(ArtistKind::Group)
+        && group_members
+            .get(&id)
+            .is_some_and(|members| !members.is_empty())
+    {
+        return Err(InternalErr::InvalidRelation);
+    }
+    Ok(())
+}
+
+impl<'a, L: LogStore> States<'a, L> {
+    // the group has to be an existing artist of kind `Group`, other than the member
+    // adding a membership the member already has is rejected
+    pub fn membership_add(
+        &self,
+        user: UserId,
+        member: ArtistId,
+        membership: ArtistMembership,
+    ) -> Result<(), InternalErr> {
+        let _notify = self.notify_on_return();
+        let group = membership.group_id;
+        if group == member {
+            return Err(InternalErr::InvalidRelation);
+        }
+        let artists = self.artists.read().named("artists")?;
+        // held from the kind check to the insert, so the group cannot stop being a group in
+        // between, see `check_kind_change`
+        let mut group_members = self.group_members.write().named("group_members")?;
+        {
+            // released before locking the member, two artists are never locked at once
+            let group = artists
+                .get(group.0)
+                .ok_or(InternalErr::InvalidArtistId(group))?
+                .read()
+                .named("artist")?;
+            let group = group
+                .as_ref()
+                .ok_or(InternalErr::InvalidArtistId(membership.group_id))?;
+            if group.kind != Some(ArtistKind::Group) {
+                return Err(InternalErr::InvalidRelation);
+            }
+        }
+        let mut artist = artists
+            .get(member.0)
+            .ok_or(InternalErr::InvalidArtistId(member))?
+            .write()
+            .named("artist")?;
+        let artist = artist
+            .as_mut()
+            .ok_or(InternalErr::InvalidArtistId(member))?;
+        if artist.memberships.contains(&membership) {
+            return Err(InternalErr::InvalidRelation);
+        }
+        self.record(
+            user,
+            &Operation::MembershipAdd {
+                member,
+                membership: membership.clone(),
+            },
+        )?;
+        artist.memberships.push(membership);
+        index(&mut group_members, group, member);
+        Ok(())
+    }
+
+    // removes every membership of `member` in `group`, `InvalidRelation` if there is none
+    pub fn membership_remove(
+        &self,
+        user: UserId,
+        member: ArtistId,
+        group: ArtistId,
+    ) -> Result<(), InternalErr> {
+        let _notify = self.notify_on_return();
+        let artists = self.artists.read().named("artists")?;
+        let mut group_members = self.group_members.write().named("group_members")?;
+        let mut artist = artists
+            .get(member.0)
+            .ok_or(InternalErr::InvalidArtistId(member))?
+            .write()
+            .named("artist")?;
+        let artist = artist
+            .as_mut()
+            .ok_or(InternalErr::InvalidArtistId(member))?;
+        if !artist.memberships.iter().any(|m| m.group_id == group) {
+            return Err(InternalErr::InvalidRelation);
+        }
+        self.record(user, &Operation::MembershipRemove { member, group })?;
+        artist.memberships.retain(|m| m.group_id != group);
+        unindex(&mut group_members, group, &member);
+        Ok(())
+    }
+
+    // records that `derived` is derived from `original`, e.g. a cover of it
+    // both tracks have to exist, i.e. their release has a track at their `TrackNum`, otherwise it
+    // is `InvalidTrackRef`; a relation that would make a song its own original, through any chain
+    // of `originals`, is rejected with `InvalidRelation`, as is an existing relation
+    pub fn song_relation_add(
+        &self,
+        user: UserId,
+        derived: TrackRef,
+        original: TrackRef,
+        kind: SongRelationKind,
+    ) -> Result<(), InternalErr> {
+        let _notify = self.notify_on_return();
+        // the outer write lock keeps the originals graph fixed during the cycle check
+        let releases = self.releases.write().named("releases")?;
+        let originals = |track: TrackRef| -> Result<Option<Vec<TrackRef>>, InternalErr> {
+            let Some(release) = releases.get(track.release_id.0) else {
+                return Ok(None);
+            };
+            Ok(release
+                .read()
+                .named("release")?
+                .tracks
+                .get(&track.track_num)
+                .map(|song| song.originals.iter().map(|(t, _)| *t).collect()))
+        };
+        if originals(original)?.is_none() {
+            return Err(InternalErr::InvalidTrackRef(original));
+        }
+        let mut visited = HashSet::new();
+        let mut pending = vec![original];
+        while let Some(track) = pending.pop() {
+            if track == derived {
+                return Err(InternalErr::InvalidRelation);
+            }
+            if visited.insert(track) {
+                pending.extend(originals(track)?.unwrap_or_default());
+            }
+        }
+
+        let mut release = releases
+            .get(derived.release_id.0)
+            .ok_or(InternalErr::InvalidTrackRef(derived))?
+            .write()
+            .named("release")?;
+        let song = release
+            .tracks
+            .get_mut(&derived.track_num)
+            .ok_or(InternalErr::InvalidTrackRef(derived))?;
+        if song.originals.contains(&(original, kind)) {
+            return Err(InternalErr::InvalidRelation);
+        }
+        self.record(
+            user,
+            &Operation::SongRelationAdd {
+                derived,
+                original,
+                kind,
+            },
+        )?;
+        song.originals.push((original, kind));
+
+        let mut derived_songs = self.derived_songs.write().named("derived_songs")?;
+        index(&mut derived_songs, original, (derived, kind));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::operation::ApiName;
+    use super::super::policy::EditPolicy;
+    use super::super::test_util::membership;
+    use super::super::wal::NaiveLogStore;
+    use super::*;
+    use std::sync::Barrier;
+    use std::thread;
+    use std::time::Duration;
+
+    // holds `membership_add` between its check of the group and the insert of the member
+    struct PauseMembershipAdd(Barrier);
+
+    impl EditPolicy for PauseMembershipAdd {
+        fn check(&self, _user: UserId, api_name: ApiName) -> Result<(), InternalErr> {
+            if api_name == ApiName::MembershipAdd {
+                self.0.wait();
+                thread::sleep(Duration::from_millis(50));
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn groups_do_not_stop_being_one_while_a_member_joins() {
+        let policy = PauseMembershipAdd(Barrier::new(2));
+        let wal = NaiveLogStore::new();
+        let states = States::new(&wal).with_edit_policy(&policy);
+        let user = UserId(1);
+        let group = states.artist_add(user, "group".to_owned()).unwrap();
+        let member = states.artist_add(user, "member".to_owned()).unwrap();
+        let seq_id = states.get_artist(group).unwrap().seq_id;
+        let to_group = ArtistMetaDataDiff::Kind(Some(ArtistKind::Group));
+        let seq_id = states
+            .artist_metadata_update(user, group, to_group, seq_id, true)
+            .unwrap();
+        let demoted = thread::scope(|s| {
+            s.spawn(|| states.membership_add(user, member, membership(group)));
+            policy.0.wait();
+            let to_solo = ArtistMetaDataDiff::Kind(Some(ArtistKind::Solo));
+            states.artist_metadata_update(user, group, to_solo, seq_id, true)
+        });
+        assert_eq!(demoted, Err(InternalErr::InvalidRelation));
+        assert_eq!(states.get_artist(member).unwrap().memberships.len(), 1);
+        assert_eq!(
+            states.get_artist(group).unwrap().kind,
+            Some(ArtistKind::Group)
+        );
+    }
+}
@@ -0,0 +1,191 @@
+// Whole-object updates of artists
+//
+// For clients that edit a full copy of an artist instead of building diffs. The seq_id is checked
+// and bumped like for a diff, over the hash of the recorded payload, see `verify_seq_chain`.
+// `seq_id` is never taken from the new value. The skip_diff fields `memberships` and
+// `profile_image`, and `tags`, have their own operations, so by default they are kept. With
+// `replace_skip_diff` they are taken from the new value as well, checked like `membership_add`
+// and `validate` would.
+//
+// The record has the whole resolved value, including the fields that were kept, and the flag, so
+// replay resolves it the same way. Replacing an artist with an equal value is not recorded. The
+// change event lists the fields that differ from the old value.
+
+use super::States;
+use super::append_vec::AppendVec;
+use super::defs::*;
+use super::derived::index_member;
+use super::errors::{InternalErr, LockName};
+use super::hashes::*;
+use super::operation::Operation;
+use super::relations::check_kind_change;
+use super::slot::Slot;
+use super::wal::LogStore;
+
+impl<'a, L: LogStore> States<'a, L> {
+    pub fn replace_artist_metadata(
+        &self,
+        user: UserId,
+        id: ArtistId,
+        mut new: ArtistMetaData,
+        mut seq_id: Hash128,
+        update_seq_id: bool,
+        replace_skip_diff: bool,
+    ) -> Result<Hash128, InternalErr> {
+        let _notify = self.notify_on_return();
+        let artists = self.artists.read().named("artists")?;
+        // held from the checks of the kinds until the new value is indexed, see
+        // `check_kind_change`
+        let mut group_members = self.group_members.write().named("group_members")?;
+        if replace_skip_diff {
+            // checked before locking the artist, two artists are never locked at once
+            self.check_memberships(&artists, id, &new.memberships)?;
+            self.check_tags(&new.tags)?;
+        }
+        let mut artist = artists
+            .get(id.0)
+            .ok_or(InternalErr::InvalidArtistId(id))?
+            .write()
+            .named("artist")?;
+        let artist = artist.as_mut().ok_or(InternalErr::InvalidArtistId(id))?;
+        new.seq_id = artist.seq_id;
+        if !replace_skip_diff {
+            new.profile_image = artist.profile_image.clone();
+            new.memberships = artist.memberships.clone();
+            new.tags = artist.tags.clone();
+        }
+        if new == *artist {
+            return Ok(artist.seq_id);
+        }
+        if artist.seq_id != seq_id {
+            return Err(InternalErr::OutdatedUpdate {
+                current: artist.seq_id,
+            });
+        }
+        check_kind_change(&group_members, id, artist.kind, new.kind)?;
+        // for the change event, in declaration order
+        let mut fields: Vec<&'static str> = artist_meta_data_diffs(artist, &new)
+            .iter()
+            .map(|diff| diff.field_name())
+            .collect();
+        fields.dedup();
+        if new.profile_image != artist.profile_image {
+            fields.push("profile_image");
+        }
+        if new.memberships != artist.memberships {
+            fields.push("memberships");
+        }
+        let op = Operation::ArtistMetadataReplace {
+            id,
+            artist: new.clone(),
+            replace_skip_diff,
+            update_seq_id,
+        };
+        let hash = op.payload_hash()?;
+        self.record_event(user, &op, Vec::new(), fields)?;
+        if update_seq_id {
+            seq_id = next_seq_id(seq_id, hash, user);
+            new.seq_id = seq_id;
+        }
+        index_member(
+            &mut group_members,
+            id,
+            &artist.memberships,
+            &new.memberships,
+        );
+        *artist = new;
+        Ok(seq_id)
+    }
+
+    // the memberships `member` may have, one by one they would be accepted by `membership_add`
+    fn check_memberships(
+        &self,
+        artists: &AppendVec<Slot<Option<ArtistMetaData>>>,
+        member: ArtistId,
+        memberships: &[ArtistMembership],
+    ) -> Result<(), InternalErr> {
+        for (i, membership) in memberships.iter().enumerate() {
+            let group = membership.group_id;
+            if group == member || memberships[..i].contains(membership) {
+                return Err(InternalErr::InvalidRelation);
+            }
+            let group = artists
+                .get(group.0)
+                .ok_or(InternalErr::InvalidArtistId(group))?
+                .read()
+                .named("artist")?;
+            let group = group
+                .as_ref()
+                .ok_or(InternalErr::InvalidArtistId(membership.group_id))?;
+            if group.kind != Some(ArtistKind::Group) {
+                return Err(InternalErr::InvalidRelation);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::test_util::membership;
+    use super::super::wal::NaiveLogStore;
+    use super::*;
+
+    #[test]
+    fn seq_id_follows_the_recorded_payload() {
+        let wal = NaiveLogStore::new();
+        let states = States::new(&wal);
+        let user = UserId(1);
+        let id = states.artist_add(user, "artist".to_owned()).unwrap();
+        let mut artist = states.get_artist(id).unwrap();
+        let seq_id = artist.seq_id;
+        artist.name = "renamed".to_owned();
+        let replaced = states
+            .replace_artist_metadata(user, id, artist, seq_id, true, false)
+            .unwrap();
+        let record = wal.read_all().unwrap().pop().unwrap();
+        assert_eq!(record.api_name, "artist_metadata_replace");
+        assert_eq!(
+            replaced,
+            verify_seq_chain(seq_id, &[(user, record.payload)])
+        );
+        assert_eq!(states.get_artist(id).unwrap().seq_id, replaced);
+    }
+
+    #[test]
+    fn change_events_list_the_changed_fields() {
+        let wal = NaiveLogStore::new();
+        let states = States::new(&wal);
+        let user = UserId(1);
+        let group = states.artist_add(user, "group".to_owned()).unwrap();
+        let seq_id = states.get_artist(group).unwrap().seq_id;
+        let diff = ArtistMetaDataDiff::Kind(Some(ArtistKind::Group));
+        states
+            .artist_metadata_update(user, group, diff, seq_id, true)
+            .unwrap();
+        let id = states.artist_add(user, "artist".to_owned()).unwrap();
+        let receiver = states.subscribe();
+
+        let mut artist = states.get_artist(id).unwrap();
+        for alias in ["a", "b"] {
+            artist.aliases.push(StringWithLocal {
+                content: alias.to_owned(),
+                local: LocalId::parse("en").unwrap(),
+            });
+        }
+        artist.birthyear = Some(2000);
+        artist.memberships = vec![membership(group)];
+        let seq_id = states
+            .replace_artist_metadata(user, id, artist.clone(), artist.seq_id, true, false)
+            .unwrap();
+        // the memberships are kept without `replace_skip_diff`
+        assert_eq!(
+            receiver.try_recv().unwrap().fields,
+            ["aliases", "birthyear"]
+        );
+        states
+            .replace_artist_metadata(user, id, artist, seq_id, true, true)
+            .unwrap();
+        assert_eq!(receiver.try_recv().unwrap().fields, ["memberships"]);
+    }
+}
@@ -0,0 +1,245 @@
+// Rebuilding `States` from WAL records
+//
+// Records are applied in log order to a fresh `States` that does not log anything, since the
+// records are already in the WAL. Every record is parsed into an `Operation` first. Updates go
+// through the same checked methods as live requests, using the seq_id of the entity at that
+// point of the log.
+//
+// Derived indexes are not in the WAL. Instead of logging recompute markers, replay rebuilds all
+// of them once the records are applied, so after `replay` and `replay_from_snapshot` they always
+// match the entities, whatever the log or snapshot contained.
+
+use super::States;
+use super::defs::*;
+use super::errors::{InternalErr, LockName};
+use super::operation::Operation;
+use super::slot::Slot;
+use super::snapshot::StateSnapshot;
+use super::wal::{DiscardLogStore, LogRecord, LogStore};
+use std::sync::RwLock;
+
+impl<'a, L: LogStore> States<'a, L> {
+    pub fn replay(wal: &'a L, records: &[LogRecord]) -> Result<States<'a, L>, InternalErr> {
+        let discard = DiscardLogStore;
+        let states = States::new(&discard);
+        states.replay_records(records)?;
+        Ok(states.with_wal(wal))
+    }
+
+    // restores the snapshot, then replays only the records after the snapshot was taken
+    pub fn replay_from_snapshot(
+        wal: &'a L,
+        snapshot: StateSnapshot,
+        records: &[LogRecord],
+    ) -> Result<States<'a, L>, InternalErr> {
+        let discard = DiscardLogStore;
+        let states = States::from_snapshot(&discard, snapshot);
+        states.replay_records(records)?;
+        Ok(states.with_wal(wal))
+    }
+}
+
+impl States<'_, DiscardLogStore> {
+    fn replay_records(&self, records: &[LogRecord]) -> Result<(), InternalErr> {
+        for record in records {
+            // records at or before an already applied sequence number are duplicates
+            let last_seq = *self.last_seq.lock().named("last_seq")?;
+            if last_seq.is_some_and(|seq| record.seq <= seq) {
+                continue;
+            }
+            let op = Operation::parse(&record.api_name, &record.payload)?;
+            self.replay_op(record.user, op)?;
+            *self.last_seq.lock().named("last_seq")? = Some(record.seq);
+        }
+        self.recompute_group_members()?;
+        self.recompute_discography()?;
+        // dangling relations are left to `validate`
+        self.recompute_derived_songs()?;
+        Ok(())
+    }
+
+    fn replay_op(&self, user: UserId, op: Operation) -> Result<(), InternalErr> {
+        match op {
+            Operation::ArtistAdd(artist) => {
+                self.artists.read().named("artists")?.push_with(|i| {
+                    self.allocate_artist_id(i);
+                    Ok::<_, InternalErr>(Slot::new(Some(artist)))
+                })?;
+            }
+            Operation::ArtistRemove(id) => {
+                self.artist_remove(user, id)?;
+            }
+            Operation::ArtistMerge { from, into } => {
+                self.artist_merge(user, from, into)?;
+            }
+            Operation::MembershipAdd { member, membership } => {
+                self.membership_add(user, member, membership)?;
+            }
+            Operation::MembershipRemove { member, group } => {
+                self.membership_remove(user, member, group)?;
+            }
+            Operation::ReleaseAdd(release) => {
+                self.releases
+                    .read()
+                    .named("releases")?
+                    .push(RwLock::new(release));
+            }
+            Operation::EventAdd(event) => {
+                self.events.read().named("events")?.push(RwLock::new(event));
+            }
+            Operation::TagAdd(name) => {
+                self.tags.write().named("tags")?.push(name);
+            }
+            Operation::LocationAdd { id, name } => {
+                self.location_add(user, id, name)?;
+            }
+            Operation::ArtistMetadataUpdate {
+                id,
+                diff,
+                update_seq_id,
+            } => {
+                let seq_id = self.get_artist(id)?.seq_id;
+                self.artist_metadata_update(user, id, diff, seq_id, update_seq_id)?;
+            }
+            Operation::ArtistMetadataUpdateBatch {
+                id,
+                diffs,
+                update_seq_id,
+            } => {
+                let seq_id = self.get_artist(id)?.seq_id;
+                self.artist_metadata_update_batch(user, id, diffs, seq_id, update_seq_id)?;
+            }
+            Operation::ArtistMetadataReplace {
+                id,
+                artist,
+                replace_skip_diff,
+                update_seq_id,
+            } => {
+                let seq_id = self.get_artist(id)?.seq_id;
+                self.replace_artist_metadata(
+                    user,
+                    id,
+                    artist,
+                    seq_id,
+                    update_seq_id,
+                    replace_skip_diff,
+                )?;
+            }
+            Operation::ReleaseMetadataUpdate {
+                id,
+                diff,
+                update_seq_id,
+            } => {
+                let seq_id = self.get_release(id)?.seq_id;
+                self.release_metadata_update(user, id, diff, seq_id, update_seq_id)?;
+            }
+            Operation::EventMetadataUpdate {
+                id,
+                diff,
+                update_seq_id,
+            } => {
+                let seq_id = self.get_event(id)?.seq_id;
+                self.event_metadata_update(user, id, diff, seq_id, update_seq_id)?;
+            }
+            Operation::TrackAdd { track, song } => {
+                let releases = self.releases.read().named("releases")?;
+                let mut release = releases
+                    .get(track.release_id.0)
+                    .ok_or(InternalErr::InvalidReleaseId(track.release_id))?
+                    .write()
+                    .named("release")?;
+                release.tracks.insert(track.track_num, song);
+            }
+            Operation::TrackUpdate {
+                track,
+                diff,
+                update_seq_id,
+            } => {
+                let seq_id = self.resolve_track(track)?.seq_id;
+                self.track_update(
+                    user,
+                    track.release_id,
+                    track.track_num,
+                    diff,
+                    seq_id,
+                    update_seq_id,
+                )?;
+            }
+            Operation::SongRelationAdd {
+                derived,
+                original,
+                kind,
+            } => {
+                self.song_relation_add(user, derived, original, kind)?;
+            }
+            Operation::UrlArchivedSet {
+                owner,
+                index,
+                archived,
+            } => {
+                self.set_url_archived(user, owner, index, archived)?;
+            }
+            Operation::ProfileImageSet { id, image } => {
+                self.set_profile_image(user, id, image)?;
+            }
+            Operation::LyricsAttach { track, local, file } => {
+                self.attach_lyrics(user, track, local, file)?;
+            }
+            Operation::LyricsDetach { track, local } => {
+                self.detach_lyrics(user, track, local)?;
+            }
+            Operation::CoverArtSet { id, image } => {
+                self.set_cover_art(user, id, image)?;
+            }
+            Operation::ReleaseImageAdd { id, image } => {
+                self.add_release_image(user, id, image)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::test_util::populate;
+    use super::super::wal::NaiveLogStore;
+    use super::*;
+    use std::collections::HashMap;
+
+    #[test]
+    fn replay_matches_live() {
+        let wal = NaiveLogStore::new();
+        let states = States::new(&wal);
+        populate(&states);
+        let replayed = States::replay(&wal, &wal.read_all().unwrap()).unwrap();
+        // entities with their seq_ids, derived indexes and the last sequence number
+        assert_eq!(replayed.snapshot().unwrap(), states.snapshot().unwrap());
+    }
+
+    #[test]
+    fn replay_rebuilds_stale_indexes_of_a_snapshot() {
+        let wal = NaiveLogStore::new();
+        let states = States::new(&wal);
+        populate(&states);
+        let mut snapshot = states.snapshot().unwrap();
+        assert!(!snapshot.group_members.is_empty());
+        assert!(!snapshot.artist_discography.is_empty());
+        assert!(!snapshot.derived_songs.is_empty());
+        snapshot.group_members = HashMap::new();
+        snapshot.artist_discography = HashMap::new();
+        snapshot.derived_songs = HashMap::new();
+        let replayed = States::replay_from_snapshot(&wal, snapshot, &[]).unwrap();
+        assert_eq!(replayed.snapshot().unwrap(), states.snapshot().unwrap());
+    }
+
+    #[test]
+    fn duplicate_records_are_skipped() {
+        let wal = NaiveLogStore::new();
+        let states = States::new(&wal);
+        populate(&states);
+        let records = wal.read_all().unwrap();
+        let twice = [&records[..], &records[..]].concat();
+        let replayed = States::replay(&wal, &twice).unwrap();
+        assert_eq!(replayed.snapshot().unwrap(), states.snapshot().unwrap());
+    }
+}
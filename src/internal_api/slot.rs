@@ -0,0 +1,181 @@
+// Storage of a single artist
+//
+// By default an artist is behind a `RwLock`. With the `arc-swap` feature, e.g. for a read
+// replica, it is behind an `ArcSwap` instead: reading an artist loads its current version and
+// never waits for a writer of that artist, and a write changes a copy that is swapped in when the
+// guard is dropped. Writers of an artist are still serialized by a mutex, rather than retrying a
+// compare-and-swap, so the seq_id check of an update sees the version it replaces.
+//
+// Only the slot is lock-free. Reads still take the outer read lock of the artists, so they wait
+// for the writers that take its write lock, e.g. `artist_merge` and `snapshot`, and every write
+// clones the whole artist, even to change one field.
+//
+// Both have the `read` and `write` of `RwLock`, with the same poisoning: a panic while writing
+// poisons the slot until `recover`.
+
+#[cfg(not(feature = "arc-swap"))]
+pub type Slot<T> = std::sync::RwLock<T>;
+
+#[cfg(not(feature = "arc-swap"))]
+pub type SlotReadGuard<'g, T> = std::sync::RwLockReadGuard<'g, T>;
+
+#[cfg(feature = "arc-swap")]
+pub use swap::{Slot, SlotReadGuard};
+
+#[cfg(feature = "arc-swap")]
+mod swap {
+    use arc_swap::{ArcSwap, Guard};
+    use std::marker::PhantomData;
+    use std::ops::{Deref, DerefMut};
+    use std::sync::{Arc, LockResult, Mutex, MutexGuard, PoisonError};
+
+    pub struct Slot<T> {
+        current: ArcSwap<T>,
+        writer: Mutex<()>,
+    }
+
+    // a version of the value, it stays the same while the guard is held
+    pub struct SlotReadGuard<'g, T> {
+        value: Guard<Arc<T>>,
+        _slot: PhantomData<&'g Slot<T>>,
+    }
+
+    // the copy that is swapped in on drop, if it was borrowed mutably
+    pub struct SlotWriteGuard<'g, T> {
+        slot: &'g Slot<T>,
+        value: Option<T>,
+        changed: bool,
+        _writer: MutexGuard<'g, ()>,
+    }
+
+    impl<T: Clone> Slot<T> {
+        pub fn new(value: T) -> Slot<T> {
+            Slot {
+                current: ArcSwap::from_pointee(value),
+                writer: Mutex::new(()),
+            }
+        }
+
+        pub fn read(&self) -> LockResult<SlotReadGuard<'_, T>> {
+            let guard = SlotReadGuard {
+                value: self.current.load(),
+                _slot: PhantomData,
+            };
+            if self.writer.is_poisoned() {
+                return Err(PoisonError::new(guard));
+            }
+            Ok(guard)
+        }
+
+        pub fn write(&self) -> LockResult<SlotWriteGuard<'_, T>> {
+            let (writer, poisoned) = match self.writer.lock() {
+                Ok(writer) => (writer, false),
+                Err(err) => (err.into_inner(), true),
+            };
+            let guard = SlotWriteGuard {
+                slot: self,
+                value: Some((**self.current.load()).clone()),
+                changed: false,
+                _writer: writer,
+            };
+            if poisoned {
+                return Err(PoisonError::new(guard));
+            }
+            Ok(guard)
+        }
+
+        pub fn is_poisoned(&self) -> bool {
+            self.writer.is_poisoned()
+        }
+
+        pub fn clear_poison(&self) {
+            self.writer.clear_poison();
+        }
+    }
+
+    impl<T> Deref for SlotReadGuard<'_, T> {
+        type Target = T;
+
+        fn deref(&self) -> &T {
+            &self.value
+        }
+    }
+
+    impl<T> Deref for SlotWriteGuard<'_, T> {
+        type Target = T;
+
+        fn deref(&self) -> &T {
+            self.value.as_ref().unwrap()
+        }
+    }
+
+    impl<T> DerefMut for SlotWriteGuard<'_, T> {
+        fn deref_mut(&mut self) -> &mut T {
+            self.changed = true;
+            self.value.as_mut().unwrap()
+        }
+    }
+
+    // also runs when a writer panics, like a `RwLock` keeps what was written before the panic
+    impl<T> Drop for SlotWriteGuard<'_, T> {
+        fn drop(&mut self) {
+            if self.changed
+                && let Some(value) = self.value.take()
+            {
+                self.slot.current.store(Arc::new(value));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::States;
+    use super::super::defs::*;
+    use super::super::wal::NaiveLogStore;
+    #[cfg(feature = "arc-swap")]
+    use super::*;
+    use std::thread;
+
+    #[cfg(feature = "arc-swap")]
+    #[test]
+    fn reads_do_not_wait_for_the_writer() {
+        let slot = Slot::new(1);
+        let mut writer = slot.write().unwrap();
+        *writer = 2;
+        // a `RwLock` would deadlock here
+        assert_eq!(*slot.read().unwrap(), 1);
+        drop(writer);
+        assert_eq!(*slot.read().unwrap(), 2);
+    }
+
+    #[test]
+    fn concurrent_reads_see_whole_writes() {
+        let wal = NaiveLogStore::new();
+        let states = States::new(&wal);
+        let user = UserId(1);
+        let id = states.artist_add(user, "0".to_owned()).unwrap();
+        thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    for _ in 0..1000 {
+                        let artist = states.get_artist(id).unwrap();
+                        let year = artist.birthyear.unwrap_or(0);
+                        assert_eq!(artist.name, year.to_string());
+                    }
+                });
+            }
+            let mut seq_id = states.get_artist(id).unwrap().seq_id;
+            for year in 1..=200u16 {
+                let diffs = ArtistMetaDataDiffSet(vec![
+                    ArtistMetaDataDiff::Name(year.to_string()),
+                    ArtistMetaDataDiff::Birthyear(Some(year)),
+                ]);
+                seq_id = states
+                    .artist_metadata_update_batch(user, id, diffs, seq_id, true)
+                    .unwrap();
+            }
+        });
+        assert_eq!(states.get_artist(id).unwrap().birthyear, Some(200));
+    }
+}
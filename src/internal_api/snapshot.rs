@@ -0,0 +1,251 @@
+// Point-in-time snapshots of `States`
+//
+// A snapshot records the sequence number of the latest WAL record it includes, so startup can
+// restore it and replay only the later records, see `States::replay_from_snapshot`.
+
+use super::States;
+use super::append_vec::AppendVec;
+use super::defs::*;
+use super::errors::{InternalErr, LockName};
+use super::slot::Slot;
+use super::wal::LogStore;
+use serde::{Deserialize, Serialize};
+use serde_with::serde_as;
+use std::collections::HashMap;
+use std::sync::RwLock;
+use std::sync::atomic::AtomicUsize;
+
+#[serde_as]
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StateSnapshot {
+    pub last_seq: Option<u64>,
+
+    // entities keep their seq_id, so pending updates based on them stay valid after restore
+    pub artists: Vec<Option<ArtistMetaData>>,
+    pub releases: Vec<Release>,
+    pub events: Vec<Event>,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    #[serde(default)]
+    pub locations: Vec<(LocationId, String)>,
+
+    #[serde_as(as = "SortedPairs")]
+    pub group_members: HashMap<ArtistId, Vec<ArtistId>>,
+    #[serde_as(as = "SortedPairs")]
+    pub artist_discography: HashMap<ArtistId, Vec<TrackRef>>,
+    #[serde_as(as = "SortedPairs")]
+    pub derived_songs: HashMap<TrackRef, Vec<(TrackRef, SongRelationKind)>>,
+}
+
+fn wrap<T>(items: Vec<T>) -> RwLock<AppendVec<RwLock<T>>> {
+    RwLock::new(items.into_iter().map(RwLock::new).collect())
+}
+
+impl<'a, L: LogStore> States<'a, L> {
+    pub fn snapshot(&self) -> Result<StateSnapshot, InternalErr> {
+        // the outer write locks wait for in-flight mutations, so the snapshot contains exactly
+        // the records up to `last_seq`
+        let artists = self.artists.write().named("artists")?;
+        let releases = self.releases.write().named("releases")?;
+        let events = self.events.write().named("events")?;
+        let tags = self.tags.write().named("tags")?;
+        let locations = self.locations.write().named("locations")?;
+        Ok(StateSnapshot {
+            last_seq: *self.last_seq.lock().named("last_seq")?,
+            artists: artists
+                .iter()
+                .map(|artist| Ok(artist.read().named("artist")?.clone()))
+                .collect::<Result<_, InternalErr>>()?,
+            releases: releases
+                .iter()
+                .map(|release| Ok(release.read().named("release")?.clone()))
+                .collect::<Result<_, InternalErr>>()?,
+            events: events
+                .iter()
+                .map(|event| Ok(event.read().named("event")?.clone()))
+                .collect::<Result<_, InternalErr>>()?,
+            tags: tags.clone(),
+            locations: locations.clone(),
+            group_members: self.group_members.read().named("group_members")?.clone(),
+            artist_discography: self
+                .artist_discography
+                .read()
+                .named("artist_discography")?
+                .clone(),
+            derived_songs: self.derived_songs.read().named("derived_songs")?.clone(),
+        })
+    }
+
+    pub fn from_snapshot(wal: &'a L, snapshot: StateSnapshot) -> States<'a, L> {
+        let states = States::new(wal);
+        States {
+            next_artist_id: AtomicUsize::new(snapshot.artists.len()),
+            artists: RwLock::new(snapshot.artists.into_iter().map(Slot::new).collect()),
+            releases: wrap(snapshot.releases),
+            events: wrap(snapshot.events),
+            tags: RwLock::new(snapshot.tags),
+            locations: RwLock::new(snapshot.locations),
+            group_members: RwLock::new(snapshot.group_members),
+            artist_discography: RwLock::new(snapshot.artist_discography),
+            derived_songs: RwLock::new(snapshot.derived_songs),
+            last_seq: snapshot.last_seq.into(),
+            ..states
+        }
+    }
+}
+
+// version of the `export_json` format, bump on any schema change of `StateSnapshot` or the types
+// in it
+pub const EXPORT_VERSION: u64 = 1;
+
+#[derive(Serialize, Deserialize)]
+struct Export {
+    version: u64,
+    catalog: StateSnapshot,
+}
+
+// only the version, so a dump of another version is reported as such rather than as a schema
+// mismatch
+#[derive(Deserialize)]
+struct ExportVersion {
+    version: Option<u64>,
+}
+
+impl<'a, L: LogStore> States<'a, L> {
+    // a point-in-time dump of the whole catalog, unlike the WAL it has no history
+    pub fn export_json(&self) -> Result<String, InternalErr> {
+        let export = Export {
+            version: EXPORT_VERSION,
+            catalog: self.snapshot()?,
+        };
+        serde_json::to_string_pretty(&export).map_err(|e| InternalErr::Other(e.to_string()))
+    }
+
+    pub fn import_json(wal: &'a L, json: &str) -> Result<States<'a, L>, InternalErr> {
+        let parse_err = |e: serde_json::Error| InternalErr::Other(e.to_string());
+        let version: ExportVersion = serde_json::from_str(json).map_err(parse_err)?;
+        check_version(version)?;
+        let export: Export = serde_json::from_str(json).map_err(parse_err)?;
+        Ok(States::from_snapshot(wal, export.catalog))
+    }
+}
+
+fn check_version(version: ExportVersion) -> Result<(), InternalErr> {
+    match version.version {
+        Some(EXPORT_VERSION) => Ok(()),
+        Some(version) => Err(InternalErr::Other(format!(
+            "unsupported export version {version}, expected {EXPORT_VERSION}"
+        ))),
+        None => Err(InternalErr::Other("export has no version".to_owned())),
+    }
+}
+
+// The same export in MessagePack, for fast cold starts. The format has to be self-describing:
+// `TrackRef` is flattened and `None` fields are skipped, which bincode or postcard cannot read
+// back. Structs are written with field names, so `#[serde(default)]` fields work as in JSON.
+#[cfg(feature = "binary")]
+impl<'a, L: LogStore> States<'a, L> {
+    pub fn snapshot_bytes(&self) -> Result<Vec<u8>, InternalErr> {
+        let export = Export {
+            version: EXPORT_VERSION,
+            catalog: self.snapshot()?,
+        };
+        rmp_serde::to_vec_named(&export).map_err(|e| InternalErr::Other(e.to_string()))
+    }
+
+    pub fn from_snapshot_bytes(wal: &'a L, bytes: &[u8]) -> Result<States<'a, L>, InternalErr> {
+        let parse_err = |e: rmp_serde::decode::Error| InternalErr::Other(e.to_string());
+        let version: ExportVersion = rmp_serde::from_slice(bytes).map_err(parse_err)?;
+        check_version(version)?;
+        let export: Export = rmp_serde::from_slice(bytes).map_err(parse_err)?;
+        Ok(States::from_snapshot(wal, export.catalog))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::test_util::populate;
+    use super::super::wal::NaiveLogStore;
+    use super::*;
+
+    #[test]
+    fn restored_snapshot_keeps_seq_ids() {
+        let wal = NaiveLogStore::new();
+        let states = States::new(&wal);
+        populate(&states);
+        let snapshot = states.snapshot().unwrap();
+        let json = serde_json::to_string(&snapshot).unwrap();
+        let restored = States::from_snapshot(&wal, serde_json::from_str(&json).unwrap());
+        assert_eq!(restored.snapshot().unwrap(), snapshot);
+
+        // an update based on a seq_id from before the restore still applies
+        let seq_id = states.get_artist(ArtistId(0)).unwrap().seq_id;
+        let diff = ArtistMetaDataDiff::Birthyear(Some(2000));
+        restored
+            .artist_metadata_update(UserId(1), ArtistId(0), diff, seq_id, true)
+            .unwrap();
+    }
+
+    #[test]
+    fn snapshot_and_later_records_match_live() {
+        let wal = NaiveLogStore::new();
+        let states = States::new(&wal);
+        populate(&states);
+        let snapshot = states.snapshot().unwrap();
+        let artist = states.artist_add(UserId(3), "later".to_owned()).unwrap();
+        states.artist_remove(UserId(3), ArtistId(1)).unwrap();
+        let seq_id = states.get_artist(artist).unwrap().seq_id;
+        let diff = ArtistMetaDataDiff::Name("renamed".to_owned());
+        states
+            .artist_metadata_update(UserId(3), artist, diff, seq_id, true)
+            .unwrap();
+
+        // the whole log, records up to `last_seq` are skipped
+        let records = wal.read_all().unwrap();
+        let replayed = States::replay_from_snapshot(&wal, snapshot, &records).unwrap();
+        assert_eq!(replayed.snapshot().unwrap(), states.snapshot().unwrap());
+    }
+
+    #[test]
+    fn json_export_round_trip() {
+        let wal = NaiveLogStore::new();
+        let states = States::new(&wal);
+        populate(&states);
+        let json = states.export_json().unwrap();
+        let imported = States::import_json(&wal, &json).unwrap();
+        assert_eq!(imported.snapshot().unwrap(), states.snapshot().unwrap());
+    }
+
+    #[test]
+    fn json_export_of_another_version_is_rejected() {
+        let wal = NaiveLogStore::new();
+        let states = States::new(&wal);
+        let mut export: serde_json::Value =
+            serde_json::from_str(&states.export_json().unwrap()).unwrap();
+        export["version"] = (EXPORT_VERSION + 1).into();
+        let err = States::import_json(&wal, &export.to_string())
+            .err()
+            .unwrap();
+        assert!(
+            err.to_string().starts_with("unsupported export version"),
+            "{err}"
+        );
+        export.as_object_mut().unwrap().remove("version");
+        let err = States::import_json(&wal, &export.to_string())
+            .err()
+            .unwrap();
+        assert_eq!(err.to_string(), "export has no version");
+    }
+
+    #[cfg(feature = "binary")]
+    #[test]
+    fn binary_snapshot_round_trip() {
+        let wal = NaiveLogStore::new();
+        let states = States::new(&wal);
+        populate(&states);
+        let bytes = states.snapshot_bytes().unwrap();
+        assert!(bytes.len() < states.export_json().unwrap().len());
+        let restored = States::from_snapshot_bytes(&wal, &bytes).unwrap();
+        assert_eq!(restored.snapshot().unwrap(), states.snapshot().unwrap());
+    }
+}
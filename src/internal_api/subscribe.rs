@@ -0,0 +1,148 @@
+// Change notifications
+//
+// Every mutation queues one `ChangeEvent` per WAL record it writes, in seq order. The events are
+// sent once the public method returns and has released all its locks, see `NotifyGuard`, so a
+// subscriber never holds up a writer while it holds a lock. A record is written before the
+// mutation is applied, so the events of a mutation that is still running, and every later event,
+// are held back until it returns: a subscriber gets the events in seq order, each after its
+// change can be read. Each subscriber gets a bounded channel, and a subscriber whose channel is
+// full or whose receiver is gone is dropped instead of blocking the writer, its receiver then
+// disconnects after the events already sent.
+
+use super::States;
+use super::defs::*;
+use super::operation::{ApiName, Operation};
+use super::wal::LogStore;
+use std::collections::{HashMap, VecDeque};
+use std::sync::MutexGuard;
+use std::sync::mpsc::{Receiver, sync_channel};
+use std::thread::{self, ThreadId};
+
+// events a subscriber can fall behind by before it is dropped
+pub const SUBSCRIBER_CAPACITY: usize = 1024;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ChangeEvent {
+    pub seq: u64,
+    pub user: UserId,
+    pub api_name: ApiName,
+    // the entities added, changed or linked, see `Operation::entities`
+    pub entities: Vec<EntityRef>,
+    // the fields changed by metadata updates, empty otherwise
+    pub fields: Vec<&'static str>,
+}
+
+#[derive(Default)]
+pub(super) struct PendingChanges {
+    // mutations running on each thread, counting nested ones
+    running: HashMap<ThreadId, usize>,
+    // queued events in seq order, with the thread of the mutation while it is running
+    events: VecDeque<(ChangeEvent, Option<ThreadId>)>,
+}
+
+// sends the queued events when dropped; created first in a mutation, so it is dropped after
+// every lock guard of the mutation
+pub(super) struct NotifyGuard<'s, 'a, L: LogStore>(&'s States<'a, L>);
+
+impl<L: LogStore> Drop for NotifyGuard<'_, '_, L> {
+    fn drop(&mut self) {
+        self.0.send_changes();
+    }
+}
+
+impl<'a, L: LogStore> States<'a, L> {
+    pub fn subscribe(&self) -> Receiver<ChangeEvent> {
+        let (sender, receiver) = sync_channel(SUBSCRIBER_CAPACITY);
+        // a poisoned list of senders is still a list of senders
+        self.subscribers
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .push(sender);
+        receiver
+    }
+
+    pub(super) fn notify_on_return(&self) -> NotifyGuard<'_, 'a, L> {
+        *self
+            .pending_changes()
+            .running
+            .entry(thread::current().id())
+            .or_default() += 1;
+        NotifyGuard(self)
+    }
+
+    // a poisoned queue is still a queue
+    fn pending_changes(&self) -> MutexGuard<'_, PendingChanges> {
+        self.pending_changes
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+    }
+
+    // called right after `op` is recorded as `seq`
+    pub(super) fn queue_change(
+        &self,
+        seq: u64,
+        user: UserId,
+        op: &Operation,
+        mut entities: Vec<EntityRef>,
+        fields: Vec<&'static str>,
+    ) {
+        entities.extend(op.entities());
+        let mut pending = self.pending_changes();
+        let thread = thread::current().id();
+        let running = pending.running.contains_key(&thread).then_some(thread);
+        let event = ChangeEvent {
+            seq,
+            user,
+            api_name: op.api_name(),
+            entities,
+            fields,
+        };
+        pending.events.push_back((event, running));
+    }
+
+    fn send_changes(&self) {
+        // the subscribers lock is taken first, so concurrent writers send in queue order
+        let mut subscribers = self.subscribers.lock().unwrap_or_else(|e| e.into_inner());
+        let mut pending = self.pending_changes();
+        let thread = thread::current().id();
+        if let Some(running) = pending.running.get_mut(&thread) {
+            *running -= 1;
+            if *running > 0 {
+                return;
+            }
+            pending.running.remove(&thread);
+        }
+        for (_, running) in pending.events.iter_mut() {
+            if *running == Some(thread) {
+                *running = None;
+            }
+        }
+        while let Some((_, None)) = pending.events.front() {
+            let (event, _) = pending.events.pop_front().unwrap();
+            subscribers.retain(|subscriber| subscriber.try_send(event.clone()).is_ok());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::wal::NaiveLogStore;
+    use super::*;
+
+    #[test]
+    fn events_wait_for_running_mutations() {
+        let wal = NaiveLogStore::new();
+        let states = States::new(&wal);
+        let receiver = states.subscribe();
+        // a mutation that has recorded, but not returned yet
+        let running = states.notify_on_return();
+        states.artist_add(UserId(1), "first".to_owned()).unwrap();
+        thread::scope(|s| {
+            s.spawn(|| states.artist_add(UserId(2), "second".to_owned()).unwrap());
+        });
+        assert!(receiver.try_recv().is_err());
+        drop(running);
+        let seqs: Vec<u64> = receiver.try_iter().map(|event| event.seq).collect();
+        assert_eq!(seqs, vec![0, 1]);
+    }
+}
@@ -0,0 +1,113 @@
+// Shared fixtures of the unit tests
+
+use super::States;
+use super::defs::*;
+use super::wal::LogStore;
+
+pub fn track(release_id: ReleaseId, track_num: u16) -> TrackRef {
+    TrackRef {
+        release_id,
+        track_num: TrackNum {
+            disc_num: 0,
+            track_num,
+        },
+    }
+}
+
+pub fn membership(group_id: ArtistId) -> ArtistMembership {
+    ArtistMembership {
+        group_id,
+        role: ArtistRole::Vocal,
+        start_date: None,
+        end_date: None,
+    }
+}
+
+// a bit of everything: every kind of entity, a removed and a merged artist, relations that end
+// up in each derived index, and updates that compaction drops
+pub fn populate<L: LogStore>(states: &States<'_, L>) {
+    let user = UserId(1);
+    let group = states.artist_add(user, "group".to_owned()).unwrap();
+    let member = states.artist_add(UserId(2), "member".to_owned()).unwrap();
+    let removed = states.artist_add(user, "removed".to_owned()).unwrap();
+    let duplicate = states.artist_add(user, "duplicate".to_owned()).unwrap();
+    let seq_id = states.get_artist(group).unwrap().seq_id;
+    let diff = ArtistMetaDataDiff::Kind(Some(ArtistKind::Group));
+    states
+        .artist_metadata_update(user, group, diff, seq_id, true)
+        .unwrap();
+    states
+        .membership_add(user, member, membership(group))
+        .unwrap();
+    states
+        .membership_add(user, duplicate, membership(group))
+        .unwrap();
+    states.artist_remove(user, removed).unwrap();
+
+    let tag = states.tag_add(user, "tag".to_owned()).unwrap();
+    let location = LocationId::parse("JP").unwrap();
+    states
+        .location_add(user, location, "Japan".to_owned())
+        .unwrap();
+    let event = states.event_add(user, "event".to_owned()).unwrap();
+    let seq_id = states.get_event(event).unwrap().seq_id;
+    states
+        .event_metadata_update(
+            user,
+            event,
+            EventDiff::Location(Some(location)),
+            seq_id,
+            true,
+        )
+        .unwrap();
+
+    let release = states.release_add(user, "release".to_owned()).unwrap();
+    let seq_id = states.get_release(release).unwrap().seq_id;
+    states
+        .release_metadata_update(
+            user,
+            release,
+            ReleaseDiff::AlbumArtists(vec![group]),
+            seq_id,
+            true,
+        )
+        .unwrap();
+    for n in 1..=2 {
+        let track = track(release, n);
+        states
+            .track_add(user, release, track.track_num, format!("track {n}"))
+            .unwrap();
+    }
+    let seq_id = states.resolve_track(track(release, 2)).unwrap().seq_id;
+    let diff = SongDiff::Artists(vec![member, duplicate]);
+    let seq_id = states
+        .track_update(
+            user,
+            release,
+            track(release, 2).track_num,
+            diff,
+            seq_id,
+            true,
+        )
+        .unwrap();
+    let diff = SongDiff::Tags(vec![tag]);
+    states
+        .track_update(
+            user,
+            release,
+            track(release, 2).track_num,
+            diff,
+            seq_id,
+            true,
+        )
+        .unwrap();
+    states
+        .song_relation_add(
+            user,
+            track(release, 2),
+            track(release, 1),
+            SongRelationKind::Cover,
+        )
+        .unwrap();
+    states.artist_merge(user, duplicate, member).unwrap();
+}
@@ -0,0 +1,170 @@
+// Consistent reads of several entities
+//
+// Lock ordering: the outer locks are always taken in the order artists, then releases, then
+// events, then tags, then locations, by writers that take more than one of them (`artist_merge`,
+// `snapshot`) and by `read_txn`, which takes the first four and does not lock the locations.
+// Writers that change several entities at once take the outer write lock, and single-entity
+// writers hold at most one entity lock at a time, so a transaction that keeps every entity lock
+// it took cannot deadlock with them.
+
+use super::States;
+use super::append_vec::AppendVec;
+use super::defs::*;
+use super::errors::{InternalErr, LockName};
+use super::slot::{Slot, SlotReadGuard};
+use super::wal::LogStore;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+use std::ops::Deref;
+use std::sync::{RwLock, RwLockReadGuard};
+
+type Guards<G> = RefCell<HashMap<usize, G>>;
+
+// An entity read through a transaction stays locked for reading until the transaction ends, so
+// every read sees the same version of it. With `arc-swap`, an artist is not locked but the
+// version first read is kept, see `Slot`. Changes that span several entities take outer write
+// locks and wait for the whole transaction, so reads are never torn across entities.
+pub struct ReadTxn<'g> {
+    artists: &'g AppendVec<Slot<Option<ArtistMetaData>>>,
+    releases: &'g AppendVec<RwLock<Release>>,
+    events: &'g AppendVec<RwLock<Event>>,
+    tags: &'g [String],
+    artist_guards: Guards<SlotReadGuard<'g, Option<ArtistMetaData>>>,
+    release_guards: Guards<RwLockReadGuard<'g, Release>>,
+    event_guards: Guards<RwLockReadGuard<'g, Event>>,
+}
+
+// reads entity `i` of `items` with `lock`, keeping the guard in `guards`
+fn read<'g, S, G: Deref<Target: Clone>>(
+    items: &'g AppendVec<S>,
+    guards: &Guards<G>,
+    i: usize,
+    lock: impl FnOnce(&'g S) -> Result<G, InternalErr>,
+) -> Result<Option<G::Target>, InternalErr> {
+    let mut guards = guards.borrow_mut();
+    let guard = match guards.entry(i) {
+        Entry::Occupied(entry) => entry.into_mut(),
+        Entry::Vacant(entry) => {
+            let Some(item) = items.get(i) else {
+                return Ok(None);
+            };
+            entry.insert(lock(item)?)
+        }
+    };
+    Ok(Some((**guard).clone()))
+}
+
+impl ReadTxn<'_> {
+    pub fn artist(&self, id: ArtistId) -> Result<ArtistMetaData, InternalErr> {
+        read(self.artists, &self.artist_guards, id.0, |artist| {
+            artist.read().named("artist")
+        })?
+        .flatten()
+        .ok_or(InternalErr::InvalidArtistId(id))
+    }
+
+    pub fn release(&self, id: ReleaseId) -> Result<Release, InternalErr> {
+        read(self.releases, &self.release_guards, id.0, |release| {
+            release.read().named("release")
+        })?
+        .ok_or(InternalErr::InvalidReleaseId(id))
+    }
+
+    pub fn event(&self, id: EventId) -> Result<Event, InternalErr> {
+        read(self.events, &self.event_guards, id.0, |event| {
+            event.read().named("event")
+        })?
+        .ok_or(InternalErr::InvalidEventId(id))
+    }
+
+    pub fn tag_name(&self, id: TagId) -> Result<String, InternalErr> {
+        self.tags
+            .get(id.0)
+            .cloned()
+            .ok_or(InternalErr::InvalidTagId(id))
+    }
+}
+
+impl<'a, L: LogStore> States<'a, L> {
+    // runs `f` with a consistent view of the states, see `ReadTxn`
+    // `f` must not call methods of these states that write, or it deadlocks. It should not call
+    // the ones that read either: they take the outer read locks again, and a `RwLock` may make
+    // them wait for a writer queued behind this transaction, which waits for the transaction
+    pub fn read_txn<R>(&self, f: impl FnOnce(&ReadTxn) -> R) -> Result<R, InternalErr> {
+        let artists = self.artists.read().named("artists")?;
+        let releases = self.releases.read().named("releases")?;
+        let events = self.events.read().named("events")?;
+        let tags = self.tags.read().named("tags")?;
+        let txn = ReadTxn {
+            artists: &artists,
+            releases: &releases,
+            events: &events,
+            tags: &tags,
+            artist_guards: RefCell::new(HashMap::new()),
+            release_guards: RefCell::new(HashMap::new()),
+            event_guards: RefCell::new(HashMap::new()),
+        };
+        Ok(f(&txn))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::wal::NaiveLogStore;
+    use super::*;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn reads_see_one_version_until_the_end() {
+        let wal = NaiveLogStore::new();
+        let states = States::new(&wal);
+        let id = states.artist_add(UserId(1), "before".to_owned()).unwrap();
+        let seq_id = states.get_artist(id).unwrap().seq_id;
+        thread::scope(|s| {
+            states
+                .read_txn(|txn| {
+                    assert_eq!(txn.artist(id).unwrap().name, "before");
+                    let writer = s.spawn(|| {
+                        let diff = ArtistMetaDataDiff::Name("after".to_owned());
+                        states.artist_metadata_update(UserId(1), id, diff, seq_id, true)
+                    });
+                    thread::sleep(Duration::from_millis(50));
+                    assert_eq!(txn.artist(id).unwrap().name, "before");
+                    writer
+                })
+                .unwrap()
+                .join()
+                .unwrap()
+                .unwrap();
+        });
+        assert_eq!(states.get_artist(id).unwrap().name, "after");
+    }
+
+    #[test]
+    fn invalid_ids_are_errors() {
+        let wal = NaiveLogStore::new();
+        let states = States::new(&wal);
+        states
+            .read_txn(|txn| {
+                assert!(matches!(
+                    txn.artist(ArtistId(0)),
+                    Err(InternalErr::InvalidArtistId(_))
+                ));
+                assert!(matches!(
+                    txn.release(ReleaseId(0)),
+                    Err(InternalErr::InvalidReleaseId(_))
+                ));
+                assert!(matches!(
+                    txn.event(EventId(0)),
+                    Err(InternalErr::InvalidEventId(_))
+                ));
+                assert!(matches!(
+                    txn.tag_name(TagId(0)),
+                    Err(InternalErr::InvalidTagId(_))
+                ));
+            })
+            .unwrap();
+    }
+}
@@ -0,0 +1,108 @@
+// Updates of single urls
+//
+// Setting the archived link of one url does not need the seq_id of its owner, so it cannot fail
+// because of an unrelated concurrent edit. It still bumps the seq_id, so an edit based on the
+// urls from before has to be rebased instead of dropping the archived link. Setting the link a
+// url already has is not recorded.
+
+use super::States;
+use super::defs::*;
+use super::errors::{InternalErr, LockName};
+use super::hashes::*;
+use super::operation::Operation;
+use super::wal::LogStore;
+
+impl<'a, L: LogStore> States<'a, L> {
+    // `index` is the position in the `urls` of `owner`, as returned by `urls_missing_archive`
+    pub fn set_url_archived(
+        &self,
+        user: UserId,
+        owner: EntityRef,
+        index: usize,
+        archived: String,
+    ) -> Result<(), InternalErr> {
+        let _notify = self.notify_on_return();
+        let op = Operation::UrlArchivedSet {
+            owner,
+            index,
+            archived: archived.clone(),
+        };
+        let set = |urls: &mut Vec<Url>, seq_id: &mut Hash128| {
+            let url = urls
+                .get_mut(index)
+                .ok_or_else(|| InternalErr::Other(format!("{owner:?} has no url {index}")))?;
+            if url.archived.as_ref() == Some(&archived) {
+                return Ok(());
+            }
+            let hash = op.payload_hash()?;
+            self.record(user, &op)?;
+            *seq_id = next_seq_id(*seq_id, hash, user);
+            url.archived = Some(archived);
+            Ok(())
+        };
+        match owner {
+            EntityRef::Artist(id) => {
+                let artists = self.artists.read().named("artists")?;
+                let mut artist = artists
+                    .get(id.0)
+                    .ok_or(InternalErr::InvalidArtistId(id))?
+                    .write()
+                    .named("artist")?;
+                let artist = artist.as_mut().ok_or(InternalErr::InvalidArtistId(id))?;
+                set(&mut artist.urls, &mut artist.seq_id)
+            }
+            EntityRef::Release(id) => {
+                let releases = self.releases.read().named("releases")?;
+                let mut release = releases
+                    .get(id.0)
+                    .ok_or(InternalErr::InvalidReleaseId(id))?
+                    .write()
+                    .named("release")?;
+                let release = &mut *release;
+                set(&mut release.urls, &mut release.seq_id)
+            }
+            EntityRef::Event(id) => {
+                let events = self.events.read().named("events")?;
+                let mut event = events
+                    .get(id.0)
+                    .ok_or(InternalErr::InvalidEventId(id))?
+                    .write()
+                    .named("event")?;
+                let event = &mut *event;
+                set(&mut event.urls, &mut event.seq_id)
+            }
+            _ => Err(InternalErr::Other(format!("{owner:?} has no urls"))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::wal::NaiveLogStore;
+    use super::*;
+
+    #[test]
+    fn seq_id_follows_the_recorded_payload() {
+        let wal = NaiveLogStore::new();
+        let states = States::new(&wal);
+        let user = UserId(1);
+        let id = states.artist_add(user, "artist".to_owned()).unwrap();
+        let seq_id = states.get_artist(id).unwrap().seq_id;
+        let url = Url {
+            url: "https://example.com".to_owned(),
+            archived: None,
+        };
+        let diff = ArtistMetaDataDiff::Urls(VecOp::Push(url));
+        let seq_id = states
+            .artist_metadata_update(user, id, diff, seq_id, true)
+            .unwrap();
+        let owner = EntityRef::Artist(id);
+        let archived = "https://archive.org/example".to_owned();
+        states.set_url_archived(user, owner, 0, archived).unwrap();
+        let record = wal.read_all().unwrap().pop().unwrap();
+        assert_eq!(
+            states.get_artist(id).unwrap().seq_id,
+            verify_seq_chain(seq_id, &[(user, record.payload)])
+        );
+    }
+}
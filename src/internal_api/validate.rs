@@ -0,0 +1,199 @@
+// Referential integrity checks
+//
+// Ids are plain indices and strings, so nothing stops an entity from referring to an id that
+// does not resolve. `validate` reports one error per dangling reference, e.g. after bulk imports.
+// A `TrackRef`, e.g. in `Song::originals`, resolves only if its release has a track at its
+// `TrackNum`, an existing release is not enough.
+
+use super::States;
+use super::defs::*;
+use super::errors::{InternalErr, LockName};
+use super::wal::LogStore;
+use std::collections::HashSet;
+
+// ids that resolve to an entity, collected once before walking the references
+pub(super) struct Existing {
+    artists: Vec<bool>,
+    events: usize,
+    tags: usize,
+    locations: HashSet<LocationId>,
+    pub(super) tracks: HashSet<TrackRef>,
+}
+
+impl Existing {
+    fn check_artist(&self, id: ArtistId, errors: &mut Vec<InternalErr>) {
+        if !self.artists.get(id.0).copied().unwrap_or(false) {
+            errors.push(InternalErr::InvalidArtistId(id));
+        }
+    }
+
+    fn check_event(&self, id: EventId, errors: &mut Vec<InternalErr>) {
+        if id.0 >= self.events {
+            errors.push(InternalErr::InvalidEventId(id));
+        }
+    }
+
+    fn check_tags(&self, tags: &[TagId], errors: &mut Vec<InternalErr>) {
+        for tag in tags.iter() {
+            if tag.0 >= self.tags {
+                errors.push(InternalErr::InvalidTagId(*tag));
+            }
+        }
+    }
+
+    fn check_location(&self, id: Option<LocationId>, errors: &mut Vec<InternalErr>) {
+        if let Some(id) = id
+            && !self.locations.contains(&id)
+        {
+            errors.push(InternalErr::InvalidLocationId(id));
+        }
+    }
+
+    fn check_track(&self, track: TrackRef, errors: &mut Vec<InternalErr>) {
+        if !self.tracks.contains(&track) {
+            errors.push(InternalErr::InvalidTrackRef(track));
+        }
+    }
+
+    pub(super) fn check_release(&self, release: &Release, errors: &mut Vec<InternalErr>) {
+        self.check_tags(&release.tags, errors);
+        for artist in release.album_artists.iter() {
+            self.check_artist(*artist, errors);
+        }
+        for (artist, _) in release.credits.iter() {
+            self.check_artist(*artist, errors);
+        }
+        if let Some(event) = release.event {
+            self.check_event(event, errors);
+        }
+        for (_, song) in release.tracks_ordered() {
+            self.check_tags(&song.tags, errors);
+            for artist in song.artists.iter() {
+                self.check_artist(*artist, errors);
+            }
+            for (artist, _) in song.credits.iter() {
+                self.check_artist(*artist, errors);
+            }
+            for (original, _) in song.originals.iter() {
+                self.check_track(*original, errors);
+            }
+        }
+    }
+}
+
+impl<'a, L: LogStore> States<'a, L> {
+    pub fn validate(&self) -> Vec<InternalErr> {
+        self.try_validate().unwrap_or_else(|e| vec![e])
+    }
+
+    pub(super) fn existing(&self) -> Result<Existing, InternalErr> {
+        let mut artists = Vec::new();
+        for artist in self.artists.read().named("artists")?.iter() {
+            artists.push(artist.read().named("artist")?.is_some());
+        }
+        let mut tracks = HashSet::new();
+        for (i, release) in self.releases.read().named("releases")?.iter().enumerate() {
+            tracks.extend(
+                release
+                    .read()
+                    .named("release")?
+                    .tracks
+                    .keys()
+                    .map(|track_num| TrackRef {
+                        release_id: ReleaseId(i),
+                        track_num: *track_num,
+                    }),
+            );
+        }
+        Ok(Existing {
+            artists,
+            events: self.events.read().named("events")?.len(),
+            tags: self.tags.read().named("tags")?.len(),
+            locations: self
+                .locations
+                .read()
+                .named("locations")?
+                .iter()
+                .map(|(id, _)| *id)
+                .collect(),
+            tracks,
+        })
+    }
+
+    fn try_validate(&self) -> Result<Vec<InternalErr>, InternalErr> {
+        let existing = self.existing()?;
+        let mut errors = Vec::new();
+        for artist in self.artists.read().named("artists")?.iter() {
+            if let Some(artist) = artist.read().named("artist")?.as_ref() {
+                existing.check_tags(&artist.tags, &mut errors);
+                existing.check_location(artist.start_loc, &mut errors);
+                existing.check_location(artist.current_loc, &mut errors);
+                for membership in artist.memberships.iter() {
+                    existing.check_artist(membership.group_id, &mut errors);
+                }
+            }
+        }
+        for release in self.releases.read().named("releases")?.iter() {
+            existing.check_release(&*release.read().named("release")?, &mut errors);
+        }
+        for event in self.events.read().named("events")?.iter() {
+            existing.check_location(event.read().named("event")?.location, &mut errors);
+        }
+        Ok(errors)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::test_util::{membership, populate, track};
+    use super::super::wal::NaiveLogStore;
+    use super::*;
+
+    #[test]
+    fn consistent_states_are_valid() {
+        let wal = NaiveLogStore::new();
+        let states = States::new(&wal);
+        populate(&states);
+        assert_eq!(states.validate(), Vec::new());
+    }
+
+    #[test]
+    fn every_dangling_reference_is_reported() {
+        let wal = NaiveLogStore::new();
+        let states = States::new(&wal);
+        populate(&states);
+        let mut snapshot = states.snapshot().unwrap();
+        let france = LocationId::parse("FR").unwrap();
+        let removed = ArtistId(2);
+        assert_eq!(snapshot.artists[removed.0], None);
+
+        let artist = snapshot.artists[0].as_mut().unwrap();
+        artist.tags.push(TagId(9));
+        artist.current_loc = Some(france);
+        artist.memberships.push(membership(removed));
+        let release = &mut snapshot.releases[0];
+        release.album_artists.push(ArtistId(99));
+        release.event = Some(EventId(9));
+        let song = release
+            .tracks
+            .get_mut(&track(ReleaseId(0), 1).track_num)
+            .unwrap();
+        song.originals
+            .push((track(ReleaseId(0), 3), SongRelationKind::Cover));
+        snapshot.events[0].location = Some(france);
+
+        let states = States::from_snapshot(&wal, snapshot);
+        assert_eq!(
+            states.validate(),
+            [
+                InternalErr::InvalidTagId(TagId(9)),
+                InternalErr::InvalidLocationId(france),
+                InternalErr::InvalidArtistId(removed),
+                InternalErr::InvalidArtistId(ArtistId(99)),
+                InternalErr::InvalidEventId(EventId(9)),
+                InternalErr::InvalidTrackRef(track(ReleaseId(0), 3)),
+                InternalErr::InvalidLocationId(france),
+            ]
+        );
+    }
+}
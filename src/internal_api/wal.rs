@@ -1,32 +1,344 @@
 use super::UserId;
-use serde::Serialize;
+use super::hashes::{Hash128, get_hash};
+use super::operation::Operation;
+use serde::{Deserialize, Serialize};
 use serde_json::to_string;
+use serde_with::{TimestampMilliSeconds, serde_as};
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
 use std::sync::Mutex;
+use std::time::SystemTime;
+
+#[serde_as]
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LogRecord {
+    pub seq: u64,
+    // time of the `record` call, serialized as unix millis
+    #[serde_as(as = "TimestampMilliSeconds<i64>")]
+    pub timestamp: SystemTime,
+    pub user: UserId,
+    pub api_name: String,
+    pub payload: String,
+}
+
+// Checksum of a record, over its JSON form, so it covers every field as stored. Stores keep it
+// next to the record and check it when reading, so a corrupted record fails the read instead of
+// being replayed.
+pub fn record_checksum(record: &LogRecord) -> Result<Hash128, String> {
+    Ok(get_hash(&to_string(record).map_err(|e| e.to_string())?))
+}
+
+fn verify_checksum(record: &LogRecord, checksum: Hash128) -> Result<(), String> {
+    if record_checksum(record)? != checksum {
+        return Err(format!(
+            "record {} is corrupted: checksum mismatch",
+            record.seq
+        ));
+    }
+    Ok(())
+}
+
+// source of record timestamps, so tests can use a deterministic clock instead of wall time
+pub trait Clock {
+    fn now(&self) -> SystemTime;
+}
+
+#[derive(Copy, Clone, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+impl<F: Fn() -> SystemTime> Clock for F {
+    fn now(&self) -> SystemTime {
+        self()
+    }
+}
 
 pub trait LogStore {
     // this function has to respect order:
     // if record(r1) happens before (and ends before) record(r2),
     // r1 should appear earlier in the record than r2
-    fn record<T: Serialize>(&self, user: UserId, api_name: &str, payload: &T)
-        -> Result<(), String>;
+    //
+    // returns the sequence number assigned to the record, which strictly increases in log order
+    fn record<T: Serialize>(
+        &self,
+        user: UserId,
+        api_name: &str,
+        payload: &T,
+    ) -> Result<u64, String>;
+
+    // typed entry point, records the api name and payload of `op`
+    fn record_op(&self, user: UserId, op: &Operation) -> Result<u64, String> {
+        self.record(user, op.api_name().as_str(), &op.payload())
+    }
+
+    // all records in log order
+    fn read_all(&self) -> Result<Vec<LogRecord>, String> {
+        Err("unsupported".to_owned())
+    }
+
+    // `(seq, api_name, payload)` of the records of one user, in log order
+    fn read_for_user(&self, user: UserId) -> Result<Vec<(u64, String, String)>, String> {
+        Ok(self
+            .read_all()?
+            .into_iter()
+            .filter(|record| record.user == user)
+            .map(|record| (record.seq, record.api_name, record.payload))
+            .collect())
+    }
+
+    // visits records in log order without collecting them
+    fn for_each_record<F: FnMut(&LogRecord)>(&self, mut f: F) -> Result<(), String> {
+        for record in self.read_all()? {
+            f(&record);
+        }
+        Ok(())
+    }
 }
 
 #[derive(Debug)]
-pub struct NaiveLogStore(Mutex<Vec<(UserId, String, String)>>);
+pub struct NaiveLogStore<C: Clock = SystemClock> {
+    // each record with its checksum, checked like the file store does
+    records: Mutex<Vec<(LogRecord, Hash128)>>,
+    clock: C,
+}
 
-impl LogStore for NaiveLogStore {
+impl NaiveLogStore {
+    pub fn new() -> NaiveLogStore {
+        NaiveLogStore::with_clock(SystemClock)
+    }
+}
+
+impl Default for NaiveLogStore {
+    fn default() -> NaiveLogStore {
+        NaiveLogStore::new()
+    }
+}
+
+impl<C: Clock> NaiveLogStore<C> {
+    pub fn with_clock(clock: C) -> NaiveLogStore<C> {
+        NaiveLogStore {
+            records: Mutex::new(Vec::new()),
+            clock,
+        }
+    }
+}
+
+impl<C: Clock> LogStore for NaiveLogStore<C> {
     fn record<T: Serialize>(
         &self,
         user: UserId,
         api_name: &str,
         payload: &T,
-    ) -> Result<(), String> {
-        let mut store = self.0.lock().map_err(|_| "Poison".to_owned())?;
-        store.push((
+    ) -> Result<u64, String> {
+        let mut store = self.records.lock().map_err(|_| "Poison".to_owned())?;
+        let seq = store.len() as u64;
+        let record = LogRecord {
+            seq,
+            timestamp: self.clock.now(),
             user,
-            api_name.into(),
-            to_string(payload).map_err(|e| e.to_string())?,
-        ));
+            api_name: api_name.into(),
+            payload: to_string(payload).map_err(|e| e.to_string())?,
+        };
+        let checksum = record_checksum(&record)?;
+        store.push((record, checksum));
+        Ok(seq)
+    }
+
+    fn read_all(&self) -> Result<Vec<LogRecord>, String> {
+        let store = self.records.lock().map_err(|_| "Poison".to_owned())?;
+        store
+            .iter()
+            .map(|(record, checksum)| {
+                verify_checksum(record, *checksum)?;
+                Ok(record.clone())
+            })
+            .collect()
+    }
+
+    // only clones the records of `user`
+    fn read_for_user(&self, user: UserId) -> Result<Vec<(u64, String, String)>, String> {
+        let store = self.records.lock().map_err(|_| "Poison".to_owned())?;
+        let mut records = Vec::new();
+        for (record, checksum) in store.iter().filter(|(record, _)| record.user == user) {
+            verify_checksum(record, *checksum)?;
+            records.push((record.seq, record.api_name.clone(), record.payload.clone()));
+        }
+        Ok(records)
+    }
+
+    fn for_each_record<F: FnMut(&LogRecord)>(&self, mut f: F) -> Result<(), String> {
+        let store = self.records.lock().map_err(|_| "Poison".to_owned())?;
+        for (record, checksum) in store.iter() {
+            verify_checksum(record, *checksum)?;
+            f(record);
+        }
         Ok(())
     }
 }
+
+// as a line of `FileLogStore`: the record fields, then its checksum
+// lines written before checksums were added have none and are read without a check
+#[derive(Serialize, Deserialize)]
+struct StoredRecord {
+    #[serde(flatten)]
+    record: LogRecord,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    checksum: Option<Hash128>,
+}
+
+// one JSON line per record
+//
+// Each record is written with a single `write_all` of the whole line and synced before `record`
+// returns, so a crash can at worst leave a partial last line. Such a line has no trailing
+// newline and is ignored by `read_all`, and dropped when the log is opened again so new records
+// are not appended to it. The sequence number of a record is its line number.
+#[derive(Debug)]
+pub struct FileLogStore<C: Clock = SystemClock> {
+    path: PathBuf,
+    // the log file and the sequence number of the next record
+    file: Mutex<(File, u64)>,
+    clock: C,
+}
+
+impl FileLogStore {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<FileLogStore, String> {
+        FileLogStore::open_with_clock(path, SystemClock)
+    }
+}
+
+impl<C: Clock> FileLogStore<C> {
+    pub fn open_with_clock<P: AsRef<Path>>(path: P, clock: C) -> Result<FileLogStore<C>, String> {
+        let path = path.as_ref().to_path_buf();
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .read(true)
+            .open(&path)
+            .map_err(|e| e.to_string())?;
+        let mut content = Vec::new();
+        (&file)
+            .read_to_end(&mut content)
+            .map_err(|e| e.to_string())?;
+        let valid = content
+            .iter()
+            .rposition(|b| *b == b'\n')
+            .map_or(0, |i| i + 1);
+        if valid < content.len() {
+            file.set_len(valid as u64).map_err(|e| e.to_string())?;
+        }
+        let next_seq = content[..valid].iter().filter(|b| **b == b'\n').count() as u64;
+        Ok(FileLogStore {
+            path,
+            file: Mutex::new((file, next_seq)),
+            clock,
+        })
+    }
+}
+
+impl<C: Clock> LogStore for FileLogStore<C> {
+    fn record<T: Serialize>(
+        &self,
+        user: UserId,
+        api_name: &str,
+        payload: &T,
+    ) -> Result<u64, String> {
+        let payload = to_string(payload).map_err(|e| e.to_string())?;
+        let mut guard = self.file.lock().map_err(|_| "Poison".to_owned())?;
+        let (file, next_seq) = &mut *guard;
+        let record = LogRecord {
+            seq: *next_seq,
+            timestamp: self.clock.now(),
+            user,
+            api_name: api_name.into(),
+            payload,
+        };
+        let stored = StoredRecord {
+            checksum: Some(record_checksum(&record)?),
+            record,
+        };
+        let mut line = to_string(&stored).map_err(|e| e.to_string())?;
+        line.push('\n');
+        file.write_all(line.as_bytes()).map_err(|e| e.to_string())?;
+        file.sync_data().map_err(|e| e.to_string())?;
+        *next_seq += 1;
+        Ok(stored.record.seq)
+    }
+
+    // fails at the first line that cannot be parsed or does not match its checksum, a line that
+    // cannot be parsed is identified by its sequence number, which is its line number
+    fn read_all(&self) -> Result<Vec<LogRecord>, String> {
+        // hold the lock so no record is half written while reading
+        let _file = self.file.lock().map_err(|_| "Poison".to_owned())?;
+        let mut content = String::new();
+        File::open(&self.path)
+            .and_then(|mut f| f.read_to_string(&mut content))
+            .map_err(|e| e.to_string())?;
+        content
+            .split_inclusive('\n')
+            .filter(|line| line.ends_with('\n'))
+            .enumerate()
+            .map(|(i, line)| {
+                let stored: StoredRecord = serde_json::from_str(line)
+                    .map_err(|e| format!("record {i} is corrupted: {e}"))?;
+                if let Some(checksum) = stored.checksum {
+                    verify_checksum(&stored.record, checksum)?;
+                }
+                Ok(stored.record)
+            })
+            .collect()
+    }
+}
+
+// drops every record, for rebuilding states from records that are already logged
+#[derive(Debug)]
+pub struct DiscardLogStore;
+
+impl LogStore for DiscardLogStore {
+    fn record<T: Serialize>(
+        &self,
+        _user: UserId,
+        _api_name: &str,
+        _payload: &T,
+    ) -> Result<u64, String> {
+        Ok(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn legacy_lines_are_read_and_mismatches_rejected() {
+        let path = std::env::temp_dir().join(format!("wal-checksum-{}.jsonl", std::process::id()));
+        let store = FileLogStore::open(&path).unwrap();
+        for tag in ["a", "b"] {
+            store.record(UserId(1), "tag_add", &tag).unwrap();
+        }
+        let records = store.read_all().unwrap();
+        assert_eq!(records.len(), 2);
+
+        let content = std::fs::read_to_string(&path).unwrap();
+        let mut lines: Vec<String> = content.lines().map(str::to_owned).collect();
+        // edited as text, the checksums do not fit in the numbers of `serde_json::Value`
+        let write = |lines: &[String]| std::fs::write(&path, lines.join("\n") + "\n").unwrap();
+        // a line written before checksums were added, the checksum is the last field
+        let checksum = lines[0].rfind(r#","checksum":"#).unwrap();
+        lines[0].replace_range(checksum.., "}");
+        write(&lines);
+        assert_eq!(store.read_all().unwrap(), records);
+
+        // a line edited after it was written
+        lines[1] = lines[1].replace(r#""payload":"\"b\"""#, r#""payload":"\"c\"""#);
+        write(&lines);
+        let err = store.read_all().unwrap_err();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(err, "record 1 is corrupted: checksum mismatch");
+    }
+}
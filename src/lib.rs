@@ -0,0 +1 @@
+pub mod internal_api;
@@ -1,5 +1,3 @@
-mod internal_api;
-
 fn main() {
     println!("Hello, world!");
 }